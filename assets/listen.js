@@ -0,0 +1,19 @@
+function listen() {
+    let full = window.location.href;
+    let dest = full.replace("dest=", "listen=");
+    let btn = document.getElementById("listen");
+    let div = document.getElementById("div1");
+    let au = document.getElementById("au");
+    au.autoplay = true;
+    try {
+        au.src=dest;
+        au.addEventListener("canplaythrough", (event) => {
+            au.play();
+        });
+        au.controls = true;
+        div.insertBefore(au, btn);
+        btn.style.display = "none";
+    } catch (e) {
+        alert(e.stack);
+    }
+}
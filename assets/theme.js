@@ -0,0 +1,18 @@
+function chg(e) {
+    if (e === "dark") {
+        document.body.style.color = "white";
+        document.body.style.backgroundColor = "black";
+    } else {
+        document.body.style.color = "black";
+        document.body.style.backgroundColor = "white";
+    }
+}
+if (window.matchMedia && window.matchMedia('(prefers-color-scheme: dark)').matches) {
+    chg("dark");
+} else {
+    chg("bright");
+}
+window.matchMedia('(prefers-color-scheme: dark)').addEventListener('change', event => {
+    const newColorScheme = event.matches ? "dark" : "light";
+    chg(newColorScheme);
+});
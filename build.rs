@@ -0,0 +1,95 @@
+// Embeds the files in assets/ for serving under /_sr/: each gets a content
+// hashed name and is precompressed with br, zstd and gzip
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// Content-Type by extension, and whether it's worth compressing
+const TYPES: [(&str, &str, bool); 4] = [
+    ("css", "text/css; charset=utf-8", true),
+    ("js", "text/javascript; charset=utf-8", true),
+    ("svg", "image/svg+xml", true),
+    ("mp3", "audio/mpeg", false),
+];
+
+// FNV-1a, stable across toolchains unlike the std hashers
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    brotli::CompressorReader::new(data, 4096, 11, 22)
+        .read_to_end(&mut out)
+        .unwrap();
+    out
+}
+
+fn zstd(data: &[u8]) -> Vec<u8> {
+    zstd::bulk::compress(data, 19).unwrap()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Write `data` to the output directory, returning an include_bytes! of it
+fn embed(out: &Path, name: &str, data: &[u8]) -> String {
+    let path = out.join(name);
+    fs::write(&path, data).unwrap();
+    format!("include_bytes!({:?})", path.to_str().unwrap())
+}
+
+fn main() {
+    println!("cargo::rerun-if-changed=assets");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut files: Vec<_> = fs::read_dir("assets")
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    files.sort();
+
+    let mut table = String::from("static ASSETS: &[Asset] = &[\n");
+    for file in files {
+        let name = file.file_name().unwrap().to_str().unwrap();
+        let (stem, ext) = name.rsplit_once('.').unwrap();
+        let (_, content_type, compress) = TYPES
+            .iter()
+            .find(|(e, _, _)| *e == ext)
+            .unwrap_or_else(|| panic!("assets/{name}: unknown type"));
+        let data = fs::read(&file).unwrap();
+        let hash = format!("{:016x}", hash(&data));
+        let path = format!("{stem}.{}.{ext}", &hash[..10]);
+
+        let variant = |suffix: &str, compressed: Vec<u8>| {
+            // Precompressed copies that don't save anything aren't kept
+            if *compress && compressed.len() < data.len() {
+                format!(
+                    "Some({})",
+                    embed(&out, &format!("{path}.{suffix}"), &compressed)
+                )
+            } else {
+                "None".to_string()
+            }
+        };
+        writeln!(
+            table,
+            "    Asset {{ name: {name:?}, path: {path:?}, etag: {etag:?}, content_type: {content_type:?}, \
+             identity: {identity}, br: {br}, zstd: {zstd}, gzip: {gzip} }},",
+            etag = format!("\"{hash}\""),
+            identity = embed(&out, &path, &data),
+            br = variant("br", brotli(&data)),
+            zstd = variant("zst", zstd(&data)),
+            gzip = variant("gz", gzip(&data)),
+        )
+        .unwrap();
+    }
+    table.push_str("];\n");
+    fs::write(out.join("assets.rs"), table).unwrap();
+}
@@ -0,0 +1,482 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use html5ever::serialize::{SerializeOpts, TraversalScope};
+use html5ever::tendril::TendrilSink;
+use markup5ever_rcdom::{Handle, NodeData, RcDom, SerializableHandle};
+use regex::RegexSet;
+use url::Url;
+
+use crate::config;
+
+const DEFAULT_LIST: &str = include_str!("../rules/blocklist.txt");
+
+// Elements that load something worth blocking through `src`
+const LOADERS: [&str; 3] = ["script", "iframe", "img"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum AttrOp {
+    Exists,
+    Equals(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+}
+
+// One compound selector such as `div.ad[data-x^="y"]`
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Compound {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+    attrs: Vec<(String, AttrOp)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+// A complex selector, e.g. `div.box > ins.ad`, stored right to left
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    last: Compound,
+    ancestors: Vec<(Combinator, Compound)>,
+}
+
+// The element a selector is tested against
+struct Element<'a> {
+    name: &'a str,
+    attrs: Vec<(String, String)>,
+}
+
+impl Element<'_> {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Compound {
+    fn matches(&self, e: &Element) -> bool {
+        self.tag.as_ref().is_none_or(|t| t == e.name)
+            && self.id.as_ref().is_none_or(|id| e.attr("id") == Some(id))
+            && self.classes.iter().all(|c| {
+                e.attr("class")
+                    .is_some_and(|v| v.split_ascii_whitespace().any(|x| x == c))
+            })
+            && self.attrs.iter().all(|(name, op)| {
+                let Some(v) = e.attr(name) else {
+                    return false;
+                };
+                match op {
+                    AttrOp::Exists => true,
+                    AttrOp::Equals(s) => v == s,
+                    AttrOp::Prefix(s) => v.starts_with(s.as_str()),
+                    AttrOp::Suffix(s) => v.ends_with(s.as_str()),
+                    AttrOp::Contains(s) => v.contains(s.as_str()),
+                }
+            })
+    }
+}
+
+fn ident(chars: &[char], i: &mut usize) -> String {
+    let start = *i;
+    while *i < chars.len() && (chars[*i].is_alphanumeric() || matches!(chars[*i], '-' | '_')) {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect()
+}
+
+fn parse_compound(chars: &[char], i: &mut usize) -> Result<Compound> {
+    let mut c = Compound::default();
+    let universal = chars.get(*i) == Some(&'*');
+    if universal {
+        *i += 1;
+    } else {
+        let tag = ident(chars, i);
+        if !tag.is_empty() {
+            c.tag = Some(tag.to_ascii_lowercase());
+        }
+    }
+    while *i < chars.len() {
+        match chars[*i] {
+            '#' => {
+                *i += 1;
+                c.id = Some(ident(chars, i));
+            }
+            '.' => {
+                *i += 1;
+                c.classes.push(ident(chars, i));
+            }
+            '[' => {
+                *i += 1;
+                let name = ident(chars, i).to_ascii_lowercase();
+                let op = match chars.get(*i) {
+                    Some(']') => None,
+                    Some('=') => Some(""),
+                    Some(o @ ('^' | '$' | '*')) if chars.get(*i + 1) == Some(&'=') => {
+                        *i += 1;
+                        Some(match o {
+                            '^' => "^",
+                            '$' => "$",
+                            _ => "*",
+                        })
+                    }
+                    _ => bail!("bad attribute selector"),
+                };
+                let op = match op {
+                    None => AttrOp::Exists,
+                    Some(kind) => {
+                        *i += 1;
+                        let value = match chars.get(*i) {
+                            Some(&q @ ('"' | '\'')) => {
+                                *i += 1;
+                                let start = *i;
+                                while *i < chars.len() && chars[*i] != q {
+                                    *i += 1;
+                                }
+                                let v: String = chars[start..*i].iter().collect();
+                                *i += 1;
+                                v
+                            }
+                            _ => ident(chars, i),
+                        };
+                        match kind {
+                            "^" => AttrOp::Prefix(value),
+                            "$" => AttrOp::Suffix(value),
+                            "*" => AttrOp::Contains(value),
+                            _ => AttrOp::Equals(value),
+                        }
+                    }
+                };
+                if chars.get(*i) != Some(&']') {
+                    bail!("unclosed attribute selector");
+                }
+                *i += 1;
+                c.attrs.push((name, op));
+            }
+            _ => break,
+        }
+    }
+    if c == Compound::default() && !universal {
+        bail!("empty selector");
+    }
+    Ok(c)
+}
+
+impl Selector {
+    // Tags, #id, .class, [attr], [attr=v], [attr^=v], [attr$=v], [attr*=v],
+    // joined by descendant or child (>) combinators
+    pub fn parse(s: &str) -> Result<Selector> {
+        let chars: Vec<char> = s.trim().chars().collect();
+        let mut i = 0;
+        let mut parts = vec![(Combinator::Descendant, parse_compound(&chars, &mut i)?)];
+        while i < chars.len() {
+            let mut combinator = Combinator::Descendant;
+            while i < chars.len() && (chars[i].is_whitespace() || chars[i] == '>') {
+                if chars[i] == '>' {
+                    combinator = Combinator::Child;
+                }
+                i += 1;
+            }
+            parts.push((combinator, parse_compound(&chars, &mut i)?));
+        }
+        // Each part is joined to the one before it by its combinator
+        let ancestors = (0..parts.len() - 1)
+            .rev()
+            .map(|k| (parts[k + 1].0, parts[k].1.clone()))
+            .collect();
+        let (_, last) = parts.pop().context("empty selector")?;
+        Ok(Selector { last, ancestors })
+    }
+
+    fn matches(&self, e: &Element, path: &[Element]) -> bool {
+        if !self.last.matches(e) {
+            return false;
+        }
+        // Walk up the ancestors, innermost first
+        let mut up = path.iter().rev();
+        for (combinator, compound) in &self.ancestors {
+            match combinator {
+                Combinator::Child => match up.next() {
+                    Some(a) if compound.matches(a) => {}
+                    _ => return false,
+                },
+                Combinator::Descendant => {
+                    if !up.any(|a| compound.matches(a)) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // The elements under `node` that match, in document order
+    pub fn select(&self, node: &Handle) -> Vec<Handle> {
+        fn walk(s: &Selector, node: &Handle, path: &mut Vec<Handle>, found: &mut Vec<Handle>) {
+            path.push(node.clone());
+            let ancestors: Vec<Element> = path.iter().filter_map(element).collect();
+            let children = node.children.borrow().clone();
+            let matched: Vec<bool> = children
+                .iter()
+                .map(|c| element(c).is_some_and(|e| s.matches(&e, &ancestors)))
+                .collect();
+            drop(ancestors);
+            for (child, matched) in children.iter().zip(matched) {
+                if matched {
+                    found.push(child.clone());
+                }
+                walk(s, child, path, found);
+            }
+            path.pop();
+        }
+        let mut found = Vec::new();
+        walk(self, node, &mut Vec::new(), &mut found);
+        found
+    }
+}
+
+// Turn an EasyList network filter into a regex over the full url
+fn filter_regex(filter: &str) -> String {
+    let (mut filter, mut re) = (filter, String::new());
+    if let Some(f) = filter.strip_prefix("||") {
+        re.push_str(r"^[a-z][a-z0-9+.-]*://([^/?#]*\.)?");
+        filter = f;
+    } else if let Some(f) = filter.strip_prefix('|') {
+        re.push('^');
+        filter = f;
+    }
+    let anchored_end = filter.ends_with('|');
+    let filter = filter.trim_end_matches('|');
+    for ch in filter.chars() {
+        match ch {
+            '*' => re.push_str(".*"),
+            '^' => re.push_str(r"(?:[^\w.%-]|$)"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if anchored_end {
+        re.push('$');
+    }
+    re
+}
+
+// EasyList style blocking: network filters for what elements load, and
+// element hiding rules applied as DOM removal.
+#[derive(Debug)]
+pub struct AdBlock {
+    block: RegexSet,
+    allow: RegexSet,
+    // (domains, selector); no domains means everywhere
+    hide: Vec<(Vec<String>, Selector)>,
+}
+
+impl AdBlock {
+    pub fn new(config: &config::AdBlock) -> Result<Self> {
+        let mut lists = Vec::new();
+        if config.defaults {
+            lists.push(DEFAULT_LIST.to_string());
+        }
+        for path in &config.lists {
+            lists.push(read(path)?);
+        }
+        let mut adblock = Self::parse(&lists.join("\n"))?;
+        for s in config.selectors.iter().flat_map(|s| s.split(',')) {
+            let selector =
+                Selector::parse(s).with_context(|| format!("bad adblock selector {s:?}"))?;
+            adblock.hide.push((Vec::new(), selector));
+        }
+        Ok(adblock)
+    }
+
+    pub fn parse(list: &str) -> Result<Self> {
+        let (mut block, mut allow, mut hide) = (Vec::new(), Vec::new(), Vec::new());
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+            if line.contains("#@#") || line.contains("#?#") || line.contains("#$#") {
+                // Exceptions and extended syntax aren't supported
+                continue;
+            }
+            if let Some((domains, selectors)) = line.split_once("##") {
+                let domains: Vec<String> = domains
+                    .split(',')
+                    .map(|d| d.trim().to_ascii_lowercase())
+                    .filter(|d| !d.is_empty() && !d.starts_with('~'))
+                    .collect();
+                // Selectors we can't parse are skipped, as browsers do
+                for selector in selectors.split(',').filter_map(|s| Selector::parse(s).ok()) {
+                    hide.push((domains.clone(), selector));
+                }
+                continue;
+            }
+            // Options such as $third-party narrow a filter; we ignore them
+            let filter = line.split('$').next().unwrap_or_default();
+            match filter.strip_prefix("@@") {
+                Some(f) if !f.is_empty() => allow.push(filter_regex(f)),
+                None if !filter.is_empty() => block.push(filter_regex(filter)),
+                _ => {}
+            }
+        }
+        Ok(AdBlock {
+            block: RegexSet::new(&block)?,
+            allow: RegexSet::new(&allow)?,
+            hide,
+        })
+    }
+
+    pub fn blocks(&self, url: &str) -> bool {
+        self.block.is_match(url) && !self.allow.is_match(url)
+    }
+
+    // Remove blocked elements from an HTML page at `page`
+    pub fn clean(&self, page: &Url, html: &str) -> Result<String> {
+        let host = page.host_str().unwrap_or_default().to_ascii_lowercase();
+        let hide: Vec<&Selector> = self
+            .hide
+            .iter()
+            .filter(|(domains, _)| {
+                domains.is_empty()
+                    || domains
+                        .iter()
+                        .any(|d| host == *d || host.ends_with(&format!(".{d}")))
+            })
+            .map(|(_, s)| s)
+            .collect();
+        let dom = html5ever::parse_document(RcDom::default(), Default::default()).one(html);
+        let mut path = Vec::new();
+        self.filter(&dom.document, page, &hide, &mut path);
+        let mut out = Vec::new();
+        html5ever::serialize(
+            &mut out,
+            &SerializableHandle::from(dom.document.clone()),
+            SerializeOpts {
+                traversal_scope: TraversalScope::ChildrenOnly(None),
+                ..Default::default()
+            },
+        )?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    fn filter(&self, node: &Handle, page: &Url, hide: &[&Selector], path: &mut Vec<Handle>) {
+        path.push(node.clone());
+        let ancestors: Vec<Element> = path.iter().filter_map(element).collect();
+        node.children.borrow_mut().retain(|child| {
+            let Some(e) = element(child) else {
+                return true;
+            };
+            let blocked = LOADERS.contains(&e.name)
+                && e.attr("src")
+                    .and_then(|src| page.join(src).ok())
+                    .is_some_and(|src| self.blocks(src.as_str()));
+            !blocked && !hide.iter().any(|s| s.matches(&e, &ancestors))
+        });
+        let children = node.children.borrow().clone();
+        for child in &children {
+            self.filter(child, page, hide, path);
+        }
+        path.pop();
+    }
+}
+
+fn element(node: &Handle) -> Option<Element<'_>> {
+    match &node.data {
+        NodeData::Element { name, attrs, .. } => Some(Element {
+            name: name.local.as_ref(),
+            attrs: attrs
+                .borrow()
+                .iter()
+                .map(|a| (a.name.local.to_string(), a.value.to_string()))
+                .collect(),
+        }),
+        _ => None,
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOKLINK: &str = include_str!("../tests/fixtures/booklink.html");
+
+    #[test]
+    fn test_selector() {
+        let e = |name, attrs: &[(&str, &str)]| Element {
+            name,
+            attrs: attrs
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        };
+        let ins = e(
+            "ins",
+            &[("class", "adsbygoogle big"), ("data-slot", "top-1")],
+        );
+        let div = e("div", &[("id", "side")]);
+        let body = e("body", &[]);
+        let s = |s| Selector::parse(s).unwrap();
+        assert!(s("ins.adsbygoogle").matches(&ins, &[]));
+        assert!(s(".big[data-slot^=top]").matches(&ins, &[]));
+        assert!(!s("div.adsbygoogle").matches(&ins, &[]));
+        assert!(s("#side ins").matches(&ins, &[body, div]));
+        let (body, div) = (e("body", &[]), e("div", &[("id", "side")]));
+        assert!(s("body > #side > ins").matches(&ins, &[body, div]));
+        let (body, div) = (e("body", &[]), e("div", &[("id", "side")]));
+        assert!(!s("body > ins").matches(&ins, &[body, div]));
+        assert!(s("*[data-slot*=\"p-\"]").matches(&ins, &[]));
+        assert!(Selector::parse("[data-x").is_err());
+        assert!(Selector::parse("").is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        let adblock = AdBlock::parse(
+            "! comment\n||ads.example.com^\n/banner/*.gif|\n@@||ads.example.com/ok.js\nexample.org##.sponsor\n",
+        )
+        .unwrap();
+        assert!(adblock.blocks("https://ads.example.com/x.js"));
+        assert!(adblock.blocks("http://cdn.ads.example.com/x.js"));
+        assert!(!adblock.blocks("https://notads.example.com/x.js"));
+        assert!(!adblock.blocks("https://ads.example.com/ok.js"));
+        assert!(adblock.blocks("https://img.example.net/banner/top.gif"));
+        assert!(!adblock.blocks("https://img.example.net/banner/top.gif?x"));
+        assert_eq!(adblock.hide[0].0, vec!["example.org".to_string()]);
+    }
+
+    #[test]
+    fn test_clean() {
+        let adblock = AdBlock::new(&config::AdBlock::default()).unwrap();
+        let url = Url::parse("https://m.booklink.me/search.php").unwrap();
+        let page = adblock.clean(&url, BOOKLINK).unwrap();
+        assert!(!page.contains("google-analytics.com"));
+        assert!(!page.contains("adsbygoogle"));
+        assert!(page.contains("第一章 开始") && page.contains(r#"<li class="hla">"#));
+        assert!(page.contains("</body>"));
+        // Text that merely mentions an ad network is left alone
+        let page = adblock
+            .clean(&url, "<p>adsbygoogle is not an ad here</p>")
+            .unwrap();
+        assert!(page.contains("adsbygoogle is not an ad here"));
+
+        // Selectors over parents and ancestors see the element's own parent
+        let adblock = AdBlock::parse("##div.box > .ad\n##body .promo").unwrap();
+        let page = adblock
+            .clean(
+                &url,
+                r#"<div class="box"><p class="ad">x</p><p>kept</p></div><p class="ad">y</p><div><span class="promo">z</span></div>"#,
+            )
+            .unwrap();
+        assert!(!page.contains(">x<") && !page.contains(">z<"));
+        assert!(page.contains("kept") && page.contains(">y<"));
+    }
+}
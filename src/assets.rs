@@ -0,0 +1,151 @@
+use anyhow::Result;
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Response, StatusCode};
+
+use crate::body::{self, Body};
+use crate::error::AppError;
+use crate::{httpcache, utils};
+
+// Where the assets are served from
+pub const PREFIX: &str = "/_sr/";
+
+// Asset names change with their content, so they never go stale
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+// A static file of the reader UI, embedded and precompressed by build.rs
+struct Asset {
+    name: &'static str,
+    // The name with a content hash, e.g. reader.3f9c0a1b2d.css
+    path: &'static str,
+    etag: &'static str,
+    content_type: &'static str,
+    identity: &'static [u8],
+    br: Option<&'static [u8]>,
+    zstd: Option<&'static [u8]>,
+    gzip: Option<&'static [u8]>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+impl Asset {
+    fn encoded(&self, coding: &str) -> Option<&'static [u8]> {
+        match coding {
+            "br" => self.br,
+            "zstd" => self.zstd,
+            "gzip" => self.gzip,
+            _ => None,
+        }
+    }
+
+    fn compressed(&self) -> bool {
+        self.br.is_some() || self.zstd.is_some() || self.gzip.is_some()
+    }
+}
+
+// The url of an asset by its file name in assets/
+pub fn url(name: &str) -> String {
+    let asset = ASSETS
+        .iter()
+        .find(|a| a.name == name)
+        .unwrap_or_else(|| panic!("no asset named {name}"));
+    format!("{PREFIX}{}", asset.path)
+}
+
+// The stylesheet and color scheme script every page of ours uses
+pub fn stylesheet() -> String {
+    format!(r#"<link rel="stylesheet" href="{}">"#, url("reader.css"))
+}
+
+pub fn script(name: &str) -> String {
+    format!(
+        r#"<script type="text/javascript" src="{}"></script>"#,
+        url(name)
+    )
+}
+
+// Serve an asset by its hashed name, in the smallest coding the client takes
+pub fn respond(req_headers: &HeaderMap, path: &str) -> Result<Response<Body>> {
+    let Some(asset) = ASSETS.iter().find(|a| a.path == path) else {
+        let mut resp = Response::new(body::full("not found"));
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(resp);
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(asset.content_type),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(CACHE_CONTROL),
+    );
+    headers.insert(header::ETAG, HeaderValue::from_static(asset.etag));
+    if asset.compressed() {
+        headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    if httpcache::not_modified(req_headers, &headers) {
+        return Ok(httpcache::not_modified_response(&headers));
+    }
+
+    let accept_encoding = utils::accept_encoding(req_headers);
+    let accepted = utils::parse_accept_encoding(&accept_encoding);
+    let best = accepted
+        .best
+        .iter()
+        .filter_map(|&c| Some((c, asset.encoded(c)?)))
+        .min_by_key(|(_, data)| data.len());
+    let data = match best {
+        Some((coding, data)) => {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(coding));
+            data
+        }
+        None if accepted.identity => asset.identity,
+        None => return Err(AppError::NotAcceptable { accept_encoding }.into()),
+    };
+    headers.insert(header::CONTENT_LENGTH, data.len().into());
+    let mut resp = Response::new(body::full(Bytes::from_static(data)));
+    *resp.headers_mut() = headers;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept_encoding: &str) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        h.insert(header::ACCEPT_ENCODING, accept_encoding.parse().unwrap());
+        h
+    }
+
+    #[test]
+    fn test_assets() {
+        let css = url("reader.css");
+        let path = css.strip_prefix(PREFIX).unwrap();
+        assert!(path.starts_with("reader.") && path.ends_with(".css"));
+
+        let resp = respond(&request("gzip, br"), path).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], CACHE_CONTROL);
+        assert_eq!(resp.headers()[header::VARY], "Accept-Encoding");
+        assert!(resp.headers().contains_key(header::CONTENT_ENCODING));
+
+        let mut req = request("identity");
+        let resp = respond(&req, path).unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        req.insert(header::IF_NONE_MATCH, resp.headers()[header::ETAG].clone());
+        let resp = respond(&req, path).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+        // Audio is stored as it is
+        let mp3 = url("silent.mp3");
+        let resp = respond(&request("br"), mp3.strip_prefix(PREFIX).unwrap()).unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!resp.headers().contains_key(header::VARY));
+
+        // Stale hashes from an older build are gone
+        let resp = respond(&request("br"), "reader.0000000000.css").unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
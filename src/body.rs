@@ -0,0 +1,304 @@
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use encoding_rs::{Decoder, Encoding};
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody, combinators::BoxBody};
+use hyper::body::{Bytes, Frame, Incoming, SizeHint};
+
+use crate::error::{self, AppError};
+
+// A response body: built in memory, or streamed from the upstream
+pub type Body = BoxBody<Bytes, hyper::Error>;
+
+pub fn full(body: impl Into<Bytes>) -> Body {
+    Full::new(body.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+pub fn stream(body: Incoming) -> Body {
+    body.boxed()
+}
+
+// Charset conversion to UTF-8, fed as the body arrives
+struct Text {
+    decoder: Decoder,
+    out: String,
+    limit: usize,
+}
+
+impl Text {
+    fn push(&mut self, buf: &[u8], last: bool) -> io::Result<()> {
+        if let Some(n) = self.decoder.max_utf8_buffer_length(buf.len()) {
+            self.out.reserve(n);
+        }
+        let _ = self.decoder.decode_to_string(buf, &mut self.out, last);
+        if self.out.len() > self.limit {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        Ok(())
+    }
+}
+
+impl Write for Text {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push(buf, false)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Undoes a Content-Encoding chunk by chunk
+enum Decompress {
+    Identity(Text),
+    Gzip(flate2::write::GzDecoder<Text>),
+    Deflate(flate2::write::DeflateDecoder<Text>),
+    Brotli(Box<brotli::DecompressorWriter<Text>>),
+    Zstd(zstd::stream::write::Decoder<'static, Text>),
+}
+
+impl Decompress {
+    fn new(encoding: &str, text: Text) -> io::Result<Self> {
+        Ok(match encoding {
+            "gzip" | "x-gzip" => Decompress::Gzip(flate2::write::GzDecoder::new(text)),
+            "deflate" => Decompress::Deflate(flate2::write::DeflateDecoder::new(text)),
+            "br" => Decompress::Brotli(Box::new(brotli::DecompressorWriter::new(text, 4096))),
+            "zstd" => Decompress::Zstd(zstd::stream::write::Decoder::new(text)?),
+            _ => Decompress::Identity(text),
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Decompress::Identity(w) => w.write_all(buf),
+            Decompress::Gzip(w) => w.write_all(buf),
+            Decompress::Deflate(w) => w.write_all(buf),
+            Decompress::Brotli(w) => w.write_all(buf),
+            Decompress::Zstd(w) => w.write_all(buf),
+        }
+    }
+
+    fn finish(self) -> io::Result<Text> {
+        match self {
+            Decompress::Identity(w) => Ok(w),
+            Decompress::Gzip(w) => w.finish(),
+            Decompress::Deflate(w) => w.finish(),
+            Decompress::Brotli(w) => w
+                .into_inner()
+                .map_err(|_| io::Error::other("truncated brotli stream")),
+            Decompress::Zstd(mut w) => {
+                w.flush()?;
+                Ok(w.into_inner())
+            }
+        }
+    }
+}
+
+// Decodes a page to UTF-8 as it arrives, giving up past `limit` bytes
+pub struct PageDecoder {
+    inner: Decompress,
+}
+
+impl PageDecoder {
+    pub fn new(content_encoding: &str, charset: &'static Encoding, limit: usize) -> Result<Self> {
+        let text = Text {
+            decoder: charset.new_decoder(),
+            out: String::new(),
+            limit,
+        };
+        Ok(PageDecoder {
+            inner: Decompress::new(content_encoding, text)?,
+        })
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.inner.write(chunk)
+    }
+
+    pub fn finish(self) -> io::Result<String> {
+        let mut text = self.inner.finish()?;
+        text.push(&[], true)?;
+        Ok(text.out)
+    }
+}
+
+// A page read in whole, or one that outgrew the limit
+pub enum Page {
+    Text(String),
+    // What was read so far and the rest, to be passed on untouched
+    TooLarge(Vec<Bytes>, Body),
+}
+
+// Read and decode a page from the upstream, within `timeout`
+pub async fn read_page(
+    url: &str,
+    mut body: Body,
+    mut decoder: PageDecoder,
+    limit: usize,
+    timeout: Duration,
+) -> Result<Page> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut seen = Vec::new();
+    let mut size = 0;
+    let decode_error = |e: io::Error| AppError::Decode {
+        url: url.to_string(),
+        reason: e.to_string(),
+    };
+    loop {
+        let frame = tokio::time::timeout_at(deadline, body.frame())
+            .await
+            .map_err(|_| AppError::UpstreamTimeout {
+                url: url.to_string(),
+            })?;
+        let Some(frame) = frame else {
+            return Ok(Page::Text(decoder.finish().map_err(decode_error)?));
+        };
+        let frame = frame.map_err(|e| AppError::UpstreamUnreachable {
+            url: url.to_string(),
+            reason: error::describe(&e),
+        })?;
+        let Ok(chunk) = frame.into_data() else {
+            continue;
+        };
+        size += chunk.len();
+        seen.push(chunk.clone());
+        if size > limit {
+            return Ok(Page::TooLarge(seen, body));
+        }
+        if let Err(e) = decoder.push(&chunk) {
+            if e.kind() == io::ErrorKind::FileTooLarge {
+                return Ok(Page::TooLarge(seen, body));
+            }
+            return Err(decode_error(e).into());
+        }
+    }
+}
+
+// The chunks already read followed by the rest of the body
+pub fn resume(seen: Vec<Bytes>, rest: Body) -> Body {
+    let head = futures_util::stream::iter(seen.into_iter().map(|b| Ok(Frame::data(b))));
+    let body = futures_util::StreamExt::chain(head, BodyStream::new(rest));
+    StreamBody::new(body).boxed()
+}
+
+type Done = Box<dyn FnOnce(Bytes) + Send + Sync>;
+
+// Passes a body on while keeping a copy, handed to `done` once the body
+// ends. Bodies over `limit` bytes aren't kept.
+struct Tee {
+    inner: Body,
+    copy: Option<Vec<u8>>,
+    limit: usize,
+    done: Option<Done>,
+}
+
+impl Tee {
+    fn finish(&mut self) {
+        if let (Some(copy), Some(done)) = (self.copy.take(), self.done.take()) {
+            done(copy.into());
+        }
+    }
+}
+
+impl hyper::body::Body for Tee {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let this = self.get_mut();
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref()
+                    && let Some(copy) = &mut this.copy
+                {
+                    if copy.len() + data.len() > this.limit {
+                        this.copy = None;
+                    } else {
+                        copy.extend_from_slice(data);
+                    }
+                }
+                // Servers stop polling once Content-Length bytes are sent
+                if this.inner.is_end_stream() {
+                    this.finish();
+                }
+            }
+            Poll::Ready(None) => this.finish(),
+            Poll::Ready(Some(Err(_))) => this.copy = None,
+            Poll::Pending => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pub fn tee(body: Body, limit: usize, done: impl FnOnce(Bytes) + Send + Sync + 'static) -> Body {
+    Tee {
+        inner: body,
+        copy: Some(Vec::new()),
+        limit,
+        done: Some(Box::new(done)),
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(
+        encoding: &str,
+        charset: &'static Encoding,
+        body: &[u8],
+        limit: usize,
+    ) -> Option<String> {
+        let mut decoder = PageDecoder::new(encoding, charset, limit).unwrap();
+        // Split inside multi-byte characters and compressed blocks
+        for chunk in body.chunks(3) {
+            decoder.push(chunk).ok()?;
+        }
+        decoder.finish().ok()
+    }
+
+    #[test]
+    fn test_page_decoder() {
+        let page = "<p>第一章 开始</p>".repeat(50);
+        let (gbk, _, _) = encoding_rs::GBK.encode(&page);
+        assert_eq!(
+            decode("", encoding_rs::GB18030, &gbk, 1 << 20).as_deref(),
+            Some(page.as_str())
+        );
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&gbk).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(
+            decode("gzip", encoding_rs::GB18030, &gzip, 1 << 20).as_deref(),
+            Some(page.as_str())
+        );
+
+        let (br, _) = crate::utils::compress_body(page.as_bytes(), &["br"]).unwrap();
+        assert_eq!(
+            decode("br", encoding_rs::UTF_8, &br, 1 << 20).as_deref(),
+            Some(page.as_str())
+        );
+        // The limit counts decoded text, so small bombs are caught
+        assert_eq!(decode("br", encoding_rs::UTF_8, &br, 100), None);
+    }
+}
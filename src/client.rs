@@ -0,0 +1,214 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use log::info;
+
+use crate::connect::Connector;
+use crate::error::{self, AppError};
+use crate::policy::AddressFilter;
+use crate::ratelimit::{self, Limiter};
+use crate::{config, logging, metrics};
+
+pub type HttpClient = Client<HttpsConnector<Connector>, Full<Bytes>>;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub tcp_keepalive: Duration,
+    pub http2_keep_alive_interval: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(config: &config::Client) -> Self {
+        ClientConfig {
+            pool_max_idle_per_host: config.pool_max_idle,
+            pool_idle_timeout: Duration::from_secs(config.pool_idle_timeout),
+            connect_timeout: Duration::from_secs(config.connect_timeout),
+            read_timeout: Duration::from_secs(config.read_timeout),
+            tcp_keepalive: Duration::from_secs(config.tcp_keepalive),
+            http2_keep_alive_interval: Duration::from_secs(config.http2_keepalive),
+        }
+    }
+}
+
+// One pooled client for every outbound request, so continuation pages and
+// proxied assets reuse connections, TLS sessions and HTTP/2 streams.
+pub struct Upstream {
+    client: HttpClient,
+    connector: Connector,
+    config: ClientConfig,
+    limiter: Limiter,
+}
+
+impl std::fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Upstream")
+            .field("config", &self.config)
+            .field("connector", &self.connector)
+            .field("limiter", &self.limiter)
+            .finish()
+    }
+}
+
+impl Upstream {
+    pub fn new(config: ClientConfig, limiter: Limiter, filter: Arc<AddressFilter>) -> Result<Self> {
+        let connector =
+            Connector::new(config.connect_timeout, config.tcp_keepalive)?.with_filter(filter);
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(connector.clone());
+        let client = Client::builder(TokioExecutor::new())
+            .pool_timer(TokioTimer::new())
+            .timer(TokioTimer::new())
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .http2_keep_alive_interval(config.http2_keep_alive_interval)
+            .http2_keep_alive_while_idle(true)
+            .build(https);
+        Ok(Upstream {
+            client,
+            connector,
+            config,
+            limiter,
+        })
+    }
+
+    // How long a whole response may take to arrive
+    pub fn read_timeout(&self) -> Duration {
+        self.config.read_timeout
+    }
+
+    // The connector behind the client, for connections that aren't plain HTTP
+    pub fn connector(&self) -> &Connector {
+        &self.connector
+    }
+
+    // Send a request within the host's rate limit, retrying GET and other
+    // safe methods on 429/503 as told by Retry-After. Gives up if the
+    // response headers take longer than the read timeout.
+    pub async fn request(&self, mut req: Request<Full<Bytes>>) -> Result<Response<Incoming>> {
+        let uri = req.uri().clone();
+        let host = uri.host().unwrap_or_default().to_string();
+        // A form sent twice may post twice
+        let retries = if req.method().is_safe() {
+            self.limiter.max_retries
+        } else {
+            0
+        };
+        let mut attempt = 0;
+        loop {
+            let retry = clone_request(&req);
+            let permit = self.limiter.acquire(&host).await;
+            let start = Instant::now();
+            let resp =
+                tokio::time::timeout(self.config.read_timeout, self.client.request(req)).await;
+            drop(permit);
+            let outcome = match &resp {
+                Ok(Ok(resp)) => resp.status().as_str().to_string(),
+                Ok(Err(_)) => "error".to_string(),
+                Err(_) => "timeout".to_string(),
+            };
+            metrics::upstream(&host, &outcome, start.elapsed());
+            logging::note_upstream(&host);
+            let resp = resp
+                .map_err(|_| AppError::UpstreamTimeout {
+                    url: uri.to_string(),
+                })?
+                .map_err(|e| match refused(&e) {
+                    Some(reason) => AppError::Forbidden {
+                        url: uri.to_string(),
+                        reason,
+                    },
+                    None => AppError::UpstreamUnreachable {
+                        url: uri.to_string(),
+                        reason: error::describe(&e),
+                    },
+                })?;
+            let status = resp.status();
+            if attempt >= retries
+                || !matches!(
+                    status,
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+                )
+            {
+                return Ok(resp);
+            }
+            let backoff = Duration::from_secs(
+                1u64.checked_shl(u32::try_from(attempt).unwrap_or(u32::MAX))
+                    .unwrap_or(u64::MAX)
+                    .min(self.limiter.max_retry_after.as_secs()),
+            );
+            let wait = match resp.headers().get(hyper::header::RETRY_AFTER) {
+                Some(v) => ratelimit::parse_retry_after(v).unwrap_or(backoff),
+                None => backoff,
+            };
+            if wait > self.limiter.max_retry_after {
+                return Ok(resp);
+            }
+            info!("{} from {}, retry in {:?}", status, host, wait);
+            self.limiter.back_off(&host, wait);
+            attempt += 1;
+            req = retry;
+        }
+    }
+
+    // Read a whole response body from `url` within the read timeout, refusing
+    // bodies over `max` bytes
+    pub async fn collect(&self, url: &str, body: Incoming, max: usize) -> Result<Bytes> {
+        let body = Limited::new(body, max).collect();
+        let bytes = tokio::time::timeout(self.config.read_timeout, body)
+            .await
+            .map_err(|_| AppError::UpstreamTimeout {
+                url: url.to_string(),
+            })?
+            .map_err(|e| {
+                if e.is::<LengthLimitError>() {
+                    AppError::Forbidden {
+                        url: url.to_string(),
+                        reason: format!("response is larger than {max} bytes"),
+                    }
+                } else {
+                    AppError::UpstreamUnreachable {
+                        url: url.to_string(),
+                        reason: error::describe(e.as_ref()),
+                    }
+                }
+            })?
+            .to_bytes();
+        Ok(bytes)
+    }
+}
+
+// Why the connector refused to connect, if that's what failed
+fn refused(e: &(dyn std::error::Error + 'static)) -> Option<String> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(AppError::Forbidden { reason, .. }) = e.downcast_ref() {
+            return Some(reason.clone());
+        }
+        source = e.source();
+    }
+    None
+}
+
+fn clone_request(req: &Request<Full<Bytes>>) -> Request<Full<Bytes>> {
+    let mut r = Request::new(req.body().clone());
+    *r.method_mut() = req.method().clone();
+    *r.uri_mut() = req.uri().clone();
+    *r.version_mut() = req.version();
+    *r.headers_mut() = req.headers().clone();
+    r
+}
@@ -0,0 +1,1249 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+// Command line flags; they win over the config file and env vars
+#[derive(Debug, Parser)]
+#[command(version, about = "A reading proxy for web novels")]
+pub struct Args {
+    /// Config file (TOML), also SIMPLEREADING_CONFIG
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on: host:port, [v6]:port or unix:/path
+    #[arg(short, long)]
+    pub listen: Option<Listen>,
+    /// Site the proxy serves
+    #[arg(long)]
+    pub booksite: Option<String>,
+    #[arg(long)]
+    pub user_agent: Option<String>,
+    /// Reader font size in px
+    #[arg(long)]
+    pub fontsize: Option<u32>,
+    #[arg(long)]
+    pub cache_dir: Option<PathBuf>,
+    /// Seconds to let requests finish on shutdown
+    #[arg(long)]
+    pub shutdown_grace: Option<u64>,
+    /// Debug logging, and links point at the listen port
+    #[arg(long)]
+    pub dev: bool,
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Fetch a chapter with all its pages once and print it to stdout
+    #[command(after_help = crate::fetch::EXIT_HELP)]
+    Fetch {
+        url: String,
+        #[arg(long, value_enum, default_value = "txt")]
+        format: crate::fetch::Format,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("empty unix socket path");
+            }
+            return Ok(Listen::Unix(path.into()));
+        }
+        let addr = s
+            .parse()
+            .with_context(|| format!("expected host:port, [v6]:port or unix:/path, got {s:?}"))?;
+        Ok(Listen::Tcp(addr))
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl TryFrom<String> for Listen {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Listen> for String {
+    fn from(l: Listen) -> String {
+        l.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub listen: Listen,
+    // Public host, port and scheme used to build links back to us.
+    // An empty port means the scheme's default.
+    pub host: String,
+    pub port: String,
+    // Defaults to https when TLS is on
+    pub scheme: Option<String>,
+    pub dev: bool,
+    pub access_log: AccessLog,
+    // Seconds in-flight requests get to finish after SIGINT/SIGTERM
+    pub shutdown_grace: u64,
+    // Addresses or CIDR ranges of reverse proxies whose X-Forwarded-For
+    // names the client. Peers on a unix socket are always trusted.
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            listen: Listen::Tcp(SocketAddr::from(([0, 0, 0, 0], 9005))),
+            host: "127.0.0.1".to_string(),
+            port: String::new(),
+            scheme: None,
+            dev: false,
+            access_log: AccessLog::Combined,
+            shutdown_grace: 30,
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+// Serving HTTPS ourselves, on when both cert and key are set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    // PEM files, reloaded when they change or on SIGHUP
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    // Plain HTTP port that redirects to HTTPS
+    pub redirect_port: Option<u16>,
+    // Seconds between checks of the files for changes
+    pub reload_secs: u64,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Tls {
+            cert: None,
+            key: None,
+            redirect_port: None,
+            reload_secs: 30,
+        }
+    }
+}
+
+// Format of the per-request access log
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLog {
+    Off,
+    Common,
+    Combined,
+    Json,
+}
+
+impl FromStr for AccessLog {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "off" => AccessLog::Off,
+            "common" => AccessLog::Common,
+            "combined" => AccessLog::Combined,
+            "json" => AccessLog::Json,
+            _ => bail!("expected off, common, combined or json"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Site {
+    pub booksite: String,
+    pub user_agent: String,
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Site {
+            booksite: "https://m.booklink.me".to_string(),
+            user_agent: "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/143.0.0.0 Safari/537.36".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reader {
+    pub fontsize: u32,
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Reader { fontsize: 17 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tts {
+    pub voice: String,
+    pub lang: String,
+    // Speaking rate relative to normal, e.g. "+50%"
+    pub rate: String,
+    // Requests a chapter is split into, synthesized in parallel
+    pub chunks: usize,
+    // Characters a client may have read out per minute; 0 for no limit
+    pub chars_per_minute: usize,
+    // ?listen= requests a client may have in flight
+    pub listens_per_client: usize,
+    // Connections to the speech service at once, across all clients
+    pub max_connections: usize,
+    // Seconds one request may take before it is given up and its
+    // connection freed
+    pub timeout: u64,
+}
+
+impl Default for Tts {
+    fn default() -> Self {
+        Tts {
+            voice: "zh-CN-XiaoxiaoNeural".to_string(),
+            lang: "zh-CN".to_string(),
+            rate: "+50.00%".to_string(),
+            chunks: 10,
+            chars_per_minute: 30000,
+            listens_per_client: 2,
+            max_connections: 30,
+            timeout: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    // Directory the cookie jar and HTTP cache are saved in across restarts
+    pub dir: Option<PathBuf>,
+    pub prefetch: bool,
+    pub prefetch_audio: bool,
+    // Prefetches running at once per reader, the oldest cancelled to make
+    // room
+    pub prefetch_per_user: usize,
+    // Chapters kept in memory; a quarter as many audio files
+    pub entries: usize,
+    // Seconds a cached chapter stays fresh
+    pub ttl: u64,
+    // Bytes of upstream responses (CSS, scripts, images, pages) kept by
+    // the HTTP cache; 0 turns it off
+    pub http_size: usize,
+    // Largest response the HTTP cache keeps
+    pub http_max_entry: usize,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            dir: None,
+            prefetch: true,
+            prefetch_audio: false,
+            prefetch_per_user: 1,
+            entries: 64,
+            ttl: 600,
+            http_size: 64 << 20,
+            http_max_entry: 4 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Replace {
+    pub from: String,
+    pub to: String,
+}
+
+fn yes() -> bool {
+    true
+}
+
+// An upstream book site. Requests are sent to the first upstream whose
+// `hosts` contain the request's host or whose `prefix` starts its path,
+// otherwise to the first one with neither.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    // Mount point such as "/booklink", stripped before forwarding
+    pub prefix: Option<String>,
+    // Set-Cookie domain to replace with ours; defaults to the url's domain
+    pub cookie_domain: Option<String>,
+    // Literal replacements in this site's pages, see also [rewrite]
+    #[serde(default)]
+    pub replace: Vec<Replace>,
+    // Follow the reader's light/dark preference
+    #[serde(default = "yes")]
+    pub dark_mode: bool,
+    // Javascript files injected at the end of each page
+    #[serde(default)]
+    pub scripts: Vec<PathBuf>,
+    // How to search the site from /search
+    pub search: Option<SiteSearch>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteSearch {
+    // Search page url, {query} is replaced by the encoded terms
+    pub url: String,
+    // Charset the site expects the terms in and serves results in, e.g. "gbk"
+    pub charset: Option<String>,
+    // Selector for the result links on the search page
+    #[serde(default = "any_link")]
+    pub results: String,
+}
+
+fn any_link() -> String {
+    "a".to_string()
+}
+
+impl Upstream {
+    // The site used when no upstream is configured
+    pub fn from_booksite(url: &str) -> Self {
+        Upstream {
+            name: "default".to_string(),
+            url: url.to_string(),
+            hosts: Vec::new(),
+            prefix: None,
+            cookie_domain: None,
+            replace: Vec::new(),
+            dark_mode: true,
+            scripts: Vec::new(),
+            search: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Proxy {
+    // Largest page rewritten, in bytes; bigger ones pass through untouched
+    pub max_body: usize,
+    // Largest request body, e.g. a form post, passed to the upstream
+    pub max_request_body: usize,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Proxy {
+            max_body: 8 << 20,
+            max_request_body: 1 << 20,
+        }
+    }
+}
+
+// Connections to the upstreams; times in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Client {
+    // Idle connections kept per host
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout: u64,
+    pub connect_timeout: u64,
+    // Until the response headers arrive, and again for the body
+    pub read_timeout: u64,
+    pub tcp_keepalive: u64,
+    pub http2_keepalive: u64,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client {
+            pool_max_idle: 16,
+            pool_idle_timeout: 90,
+            connect_timeout: 10,
+            read_timeout: 30,
+            tcp_keepalive: 60,
+            http2_keepalive: 30,
+        }
+    }
+}
+
+// Requests to each upstream host. Rates are "rate[:burst[:concurrency]]":
+// requests per second, requests in a burst and requests in flight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub rate: String,
+    // Rates for particular hosts, subdomains included
+    pub hosts: BTreeMap<String, String>,
+    // Random delay added to each request, up to this many ms
+    pub jitter_ms: u64,
+    // Retries after a 429 or 503, waiting as told by Retry-After
+    pub max_retries: usize,
+    // Longer Retry-Afters are passed on instead of waited out
+    pub max_retry_after_ms: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            rate: "2:4:4".to_string(),
+            hosts: BTreeMap::new(),
+            jitter_ms: 250,
+            max_retries: 3,
+            max_retry_after_ms: 60_000,
+        }
+    }
+}
+
+// What the reader may fetch from a ?dest= or ?listen= url
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fetch {
+    pub schemes: Vec<String>,
+    // Host names (subdomains included), addresses and CIDR ranges, as in
+    // NO_PROXY. When allow_hosts is set nothing else is fetched; the
+    // upstream sites are always allowed.
+    pub allow_hosts: Vec<String>,
+    pub deny_hosts: Vec<String>,
+    // Refuse hosts resolving to loopback, private, link-local and other
+    // non-public addresses, after each redirect too
+    pub block_private: bool,
+    // Largest page fetched, in bytes
+    pub max_size: usize,
+}
+
+impl Default for Fetch {
+    fn default() -> Self {
+        Fetch {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allow_hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+            block_private: true,
+            max_size: 16 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cookies {
+    // Keep the cookies sites set on pages fetched for the reader and send
+    // them back, as a browser would
+    pub jar: bool,
+    // cookies.txt exported from a browser with the logins to use; implies jar
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    // Send the reader to `engine`
+    Redirect,
+    // Search the upstreams that have a `search` table and list the results
+    Sites,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Search {
+    pub mode: SearchMode,
+    // Search url, {query} is replaced by the encoded terms
+    pub engine: String,
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Search {
+            mode: SearchMode::Redirect,
+            // Where the booksite's search box has always sent readers
+            engine: "https://kagi.com/search?q={query}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    Off,
+    // A shared token, as a Bearer token or once in ?token= from a browser
+    Token,
+    // HTTP Basic against the htpasswd users
+    Basic,
+    // A login page for the htpasswd users, then a signed cookie
+    Session,
+}
+
+impl FromStr for AuthMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "off" => AuthMode::Off,
+            "token" => AuthMode::Token,
+            "basic" => AuthMode::Basic,
+            "session" => AuthMode::Session,
+            _ => bail!("expected off, token, basic or session"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub mode: AuthMode,
+    pub token: String,
+    // user:hash lines, bcrypt or {SHA}
+    pub htpasswd: Option<PathBuf>,
+    // Key signing session cookies; a random one each start when empty,
+    // which logs everyone out on restart
+    pub secret: String,
+    // Days a login lasts
+    pub session_days: u64,
+    // Paths served without logging in
+    pub allow: Vec<String>,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            mode: AuthMode::Off,
+            token: String::new(),
+            htpasswd: None,
+            secret: String::new(),
+            session_days: 30,
+            allow: vec!["/healthz".to_string(), "/readyz".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Health {
+    // Have /readyz check that the speech service accepts connections
+    pub tts_probe: bool,
+    // Seconds the probe may take
+    pub tts_timeout: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            tts_probe: false,
+            tts_timeout: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rewrite {
+    // Rules file, applied after the built-in rules
+    pub rules: Option<PathBuf>,
+    // Apply the built-in rules (ad removal, booklink fixes)
+    pub defaults: bool,
+    // Seconds between checks of the rules file for changes
+    pub reload_secs: u64,
+}
+
+impl Default for Rewrite {
+    fn default() -> Self {
+        Rewrite {
+            rules: None,
+            defaults: true,
+            reload_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdBlock {
+    pub enabled: bool,
+    // Use the built-in list from rules/blocklist.txt
+    pub defaults: bool,
+    // EasyList style filter lists
+    pub lists: Vec<PathBuf>,
+    // CSS selectors of elements to remove from every page
+    pub selectors: Vec<String>,
+}
+
+impl Default for AdBlock {
+    fn default() -> Self {
+        AdBlock {
+            enabled: true,
+            defaults: true,
+            lists: Vec::new(),
+            selectors: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub tls: Tls,
+    pub site: Site,
+    pub reader: Reader,
+    pub tts: Tts,
+    pub cache: Cache,
+    pub proxy: Proxy,
+    pub client: Client,
+    pub rate_limit: RateLimit,
+    pub rewrite: Rewrite,
+    pub adblock: AdBlock,
+    pub search: Search,
+    pub cookies: Cookies,
+    pub health: Health,
+    pub auth: Auth,
+    pub fetch: Fetch,
+    pub upstream: Vec<Upstream>,
+}
+
+impl Config {
+    // Defaults, then the config file, then env vars, then flags
+    pub fn load(args: &Args) -> Result<Self> {
+        let path = args
+            .config
+            .clone()
+            .or_else(|| std::env::var_os("SIMPLEREADING_CONFIG").map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Config::parse(&text).with_context(|| format!("bad config {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.apply_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    // Env vars take the names the server has always used
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        fn parsed<T: FromStr>(name: &str, v: String) -> Result<T>
+        where
+            T::Err: fmt::Display,
+        {
+            v.parse()
+                .map_err(|e| anyhow::anyhow!("{name}: invalid value {v:?}: {e}"))
+        }
+        if let Some(v) = var("LISTEN") {
+            self.server.listen = parsed("LISTEN", v)?;
+        }
+        if var("LISTEN_LOCAL").is_some()
+            && let Listen::Tcp(addr) = &mut self.server.listen
+        {
+            addr.set_ip([127, 0, 0, 1].into());
+        }
+        if let Some(v) = var("LOCAL_PORT") {
+            let port = parsed("LOCAL_PORT", v)?;
+            match &mut self.server.listen {
+                Listen::Tcp(addr) => addr.set_port(port),
+                Listen::Unix(_) => bail!("LOCAL_PORT: listening on a unix socket"),
+            }
+        }
+        if let Some(v) = var("HOST") {
+            self.server.host = v;
+        }
+        if let Some(v) = var("PORT") {
+            self.server.port = v;
+        }
+        if let Some(v) = var("SCHEME") {
+            self.server.scheme = Some(v);
+        }
+        if var("DEV").is_some() {
+            self.server.dev = true;
+        }
+        if let Some(v) = var("TLS_CERT") {
+            self.tls.cert = Some(v.into());
+        }
+        if let Some(v) = var("TLS_KEY") {
+            self.tls.key = Some(v.into());
+        }
+        if let Some(v) = var("TLS_REDIRECT_PORT") {
+            self.tls.redirect_port = Some(parsed("TLS_REDIRECT_PORT", v)?);
+        }
+        if let Some(v) = var("TLS_RELOAD_SECS") {
+            self.tls.reload_secs = parsed("TLS_RELOAD_SECS", v)?;
+        }
+        if let Some(v) = var("BOOKSITE") {
+            self.site.booksite = v;
+        }
+        if let Some(v) = var("USER_AGENT") {
+            self.site.user_agent = v;
+        }
+        if let Some(v) = var("FONTSIZE") {
+            self.reader.fontsize = parsed("FONTSIZE", v)?;
+        }
+        if let Some(v) = var("TTS_VOICE") {
+            self.tts.voice = v;
+        }
+        if let Some(v) = var("TTS_RATE") {
+            self.tts.rate = v;
+        }
+        if let Some(v) = var("TTS_TIMEOUT") {
+            self.tts.timeout = parsed("TTS_TIMEOUT", v)?;
+        }
+        if let Some(v) = var("CACHE_DIR") {
+            self.cache.dir = Some(v.into());
+        }
+        if let Some(v) = var("HTTP_CACHE_SIZE") {
+            self.cache.http_size = parsed("HTTP_CACHE_SIZE", v)?;
+        }
+        if let Some(v) = var("MAX_BODY_SIZE") {
+            self.proxy.max_body = parsed("MAX_BODY_SIZE", v)?;
+        }
+        if let Some(v) = var("MAX_REQUEST_BODY") {
+            self.proxy.max_request_body = parsed("MAX_REQUEST_BODY", v)?;
+        }
+        if let Some(v) = var("POOL_MAX_IDLE") {
+            self.client.pool_max_idle = parsed("POOL_MAX_IDLE", v)?;
+        }
+        if let Some(v) = var("POOL_IDLE_TIMEOUT") {
+            self.client.pool_idle_timeout = parsed("POOL_IDLE_TIMEOUT", v)?;
+        }
+        if let Some(v) = var("CONNECT_TIMEOUT") {
+            self.client.connect_timeout = parsed("CONNECT_TIMEOUT", v)?;
+        }
+        if let Some(v) = var("READ_TIMEOUT") {
+            self.client.read_timeout = parsed("READ_TIMEOUT", v)?;
+        }
+        if let Some(v) = var("TCP_KEEPALIVE") {
+            self.client.tcp_keepalive = parsed("TCP_KEEPALIVE", v)?;
+        }
+        if let Some(v) = var("HTTP2_KEEPALIVE") {
+            self.client.http2_keepalive = parsed("HTTP2_KEEPALIVE", v)?;
+        }
+        if let Some(v) = var("RATE_LIMIT") {
+            self.rate_limit.rate = v;
+        }
+        // RATE_LIMITS="m.booklink.me=5:10:8,www.example.com=0.5"
+        if let Some(v) = var("RATE_LIMITS") {
+            for pair in v.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let Some((host, rate)) = pair.split_once('=') else {
+                    bail!("RATE_LIMITS: expected host=rate, got {pair:?}");
+                };
+                self.rate_limit
+                    .hosts
+                    .insert(host.trim().to_ascii_lowercase(), rate.trim().to_string());
+            }
+        }
+        if let Some(v) = var("RATE_JITTER_MS") {
+            self.rate_limit.jitter_ms = parsed("RATE_JITTER_MS", v)?;
+        }
+        if let Some(v) = var("MAX_RETRIES") {
+            self.rate_limit.max_retries = parsed("MAX_RETRIES", v)?;
+        }
+        if let Some(v) = var("MAX_RETRY_AFTER_MS") {
+            self.rate_limit.max_retry_after_ms = parsed("MAX_RETRY_AFTER_MS", v)?;
+        }
+        if let Some(v) = var("REWRITE_RULES") {
+            self.rewrite.rules = Some(v.into());
+        }
+        if let Some(v) = var("ACCESS_LOG") {
+            self.server.access_log = parsed("ACCESS_LOG", v)?;
+        }
+        if let Some(v) = var("SHUTDOWN_GRACE") {
+            self.server.shutdown_grace = parsed("SHUTDOWN_GRACE", v)?;
+        }
+        if let Some(v) = var("TRUSTED_PROXIES") {
+            self.server.trusted_proxies = v
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(v) = var("SEARCH_ENGINE") {
+            self.search.engine = v;
+        }
+        if let Some(v) = var("AUTH_MODE") {
+            self.auth.mode = parsed("AUTH_MODE", v)?;
+        }
+        if let Some(v) = var("AUTH_TOKEN") {
+            self.auth.token = v;
+        }
+        if let Some(v) = var("AUTH_HTPASSWD") {
+            self.auth.htpasswd = Some(v.into());
+        }
+        if let Some(v) = var("AUTH_SECRET") {
+            self.auth.secret = v;
+        }
+        if let Some(v) = var("COOKIES_FILE") {
+            self.cookies.file = Some(v.into());
+        }
+        if var("NO_ADBLOCK").is_some() {
+            self.adblock.enabled = false;
+        }
+        if var("NO_PREFETCH").is_some() {
+            self.cache.prefetch = false;
+        }
+        if var("PREFETCH_AUDIO").is_some() {
+            self.cache.prefetch_audio = true;
+        }
+        if let Some(v) = var("PREFETCH_PER_USER") {
+            self.cache.prefetch_per_user = parsed("PREFETCH_PER_USER", v)?;
+        }
+        if let Some(v) = var("PREFETCH_CACHE_SIZE") {
+            self.cache.entries = parsed("PREFETCH_CACHE_SIZE", v)?;
+        }
+        if let Some(v) = var("PREFETCH_TTL") {
+            self.cache.ttl = parsed("PREFETCH_TTL", v)?;
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(listen) = &args.listen {
+            self.server.listen = listen.clone();
+        }
+        if let Some(booksite) = &args.booksite {
+            self.site.booksite = booksite.clone();
+        }
+        if let Some(ua) = &args.user_agent {
+            self.site.user_agent = ua.clone();
+        }
+        if let Some(fontsize) = args.fontsize {
+            self.reader.fontsize = fontsize;
+        }
+        if let Some(dir) = &args.cache_dir {
+            self.cache.dir = Some(dir.clone());
+        }
+        if let Some(grace) = args.shutdown_grace {
+            self.server.shutdown_grace = grace;
+        }
+        if args.dev {
+            self.server.dev = true;
+        }
+    }
+
+    // The configured upstreams, or just site.booksite
+    pub fn upstreams(&self) -> Vec<Upstream> {
+        if self.upstream.is_empty() {
+            vec![Upstream::from_booksite(&self.site.booksite)]
+        } else {
+            self.upstream.clone()
+        }
+    }
+
+    // Check everything up front and report all problems at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let http_url = |s: &str| {
+            url::Url::parse(s)
+                .is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.host().is_some())
+        };
+        if !http_url(&self.site.booksite) {
+            problems.push(format!(
+                "site.booksite: expected an http(s) url, got {:?}",
+                self.site.booksite
+            ));
+        }
+        let mut names = std::collections::HashSet::new();
+        for (i, u) in self.upstream.iter().enumerate() {
+            if !names.insert(&u.name) {
+                problems.push(format!("upstream[{i}].name: duplicate name {:?}", u.name));
+            }
+            if !http_url(&u.url) {
+                problems.push(format!(
+                    "upstream[{i}].url: expected an http(s) url, got {:?}",
+                    u.url
+                ));
+            }
+            if let Some(prefix) = &u.prefix
+                && (!prefix.starts_with('/') || prefix.ends_with('/'))
+            {
+                problems.push(format!(
+                    "upstream[{i}].prefix: expected a path like \"/name\", got {prefix:?}"
+                ));
+            }
+            for script in u.scripts.iter().filter(|s| !s.is_file()) {
+                problems.push(format!(
+                    "upstream[{i}].scripts: {} is not a file",
+                    script.display()
+                ));
+            }
+            if let Some(search) = &u.search {
+                if !http_url(&search.url) || !search.url.contains("{query}") {
+                    problems.push(format!(
+                        "upstream[{i}].search.url: expected an http(s) url with {{query}}, got {:?}",
+                        search.url
+                    ));
+                }
+                if let Some(charset) = &search.charset
+                    && encoding_rs::Encoding::for_label(charset.as_bytes()).is_none()
+                {
+                    problems.push(format!(
+                        "upstream[{i}].search.charset: unknown charset {charset:?}"
+                    ));
+                }
+                if let Err(e) = crate::adblock::Selector::parse(&search.results) {
+                    problems.push(format!("upstream[{i}].search.results: {e}"));
+                }
+            }
+        }
+        if !http_url(&self.search.engine) || !self.search.engine.contains("{query}") {
+            problems.push(format!(
+                "search.engine: expected an http(s) url with {{query}}, got {:?}",
+                self.search.engine
+            ));
+        }
+        if self.search.mode == SearchMode::Sites
+            && self.upstreams().iter().all(|u| u.search.is_none())
+        {
+            problems.push("search.mode: sites needs an upstream with a search table".to_string());
+        }
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => {
+                for (name, file) in [("cert", cert), ("key", key)] {
+                    if !file.is_file() {
+                        problems.push(format!("tls.{name}: {} is not a file", file.display()));
+                    }
+                }
+            }
+            (None, None) => {
+                if self.tls.redirect_port.is_some() {
+                    problems.push("tls.redirect_port: needs tls.cert and tls.key".to_string());
+                }
+            }
+            _ => problems.push("tls: cert and key must be set together".to_string()),
+        }
+        if self.tls.reload_secs == 0 {
+            problems.push("tls.reload_secs: must be at least 1".to_string());
+        }
+        if self.client.connect_timeout == 0 {
+            problems.push("client.connect_timeout: must be at least 1 second".to_string());
+        }
+        if self.server.shutdown_grace > 3600 {
+            problems.push(format!(
+                "server.shutdown_grace: expected at most 3600 seconds, got {}",
+                self.server.shutdown_grace
+            ));
+        }
+        for proxy in &self.server.trusted_proxies {
+            let (ip, bits) = proxy.split_once('/').unwrap_or((proxy, "0"));
+            if ip.parse::<std::net::IpAddr>().is_err() || bits.parse::<u8>().is_err() {
+                problems.push(format!(
+                    "server.trusted_proxies: expected an address or CIDR range, got {proxy:?}"
+                ));
+            }
+        }
+        if self.client.read_timeout == 0 {
+            problems.push("client.read_timeout: must be at least 1 second".to_string());
+        }
+        if let Err(e) = crate::ratelimit::Limiter::new(&self.rate_limit) {
+            problems.push(format!("rate_limit: {e:#}"));
+        }
+        if self.proxy.max_body < 1024 {
+            problems.push(format!(
+                "proxy.max_body: expected at least 1024, got {}",
+                self.proxy.max_body
+            ));
+        }
+        if let Some(file) = &self.cookies.file
+            && !file.is_file()
+        {
+            problems.push(format!("cookies.file: {} is not a file", file.display()));
+        }
+        if self.site.user_agent.trim().is_empty() {
+            problems.push("site.user_agent: must not be empty".to_string());
+        }
+        if self.server.host.trim().is_empty() {
+            problems.push("server.host: must not be empty".to_string());
+        }
+        if !self.server.port.is_empty() && self.server.port.parse::<u16>().is_err() {
+            problems.push(format!(
+                "server.port: expected a port number, got {:?}",
+                self.server.port
+            ));
+        }
+        if let Some(scheme) = &self.server.scheme
+            && scheme != "http"
+            && scheme != "https"
+        {
+            problems.push(format!(
+                "server.scheme: expected http or https, got {scheme:?}"
+            ));
+        }
+        if !(8..=72).contains(&self.reader.fontsize) {
+            problems.push(format!(
+                "reader.fontsize: expected 8 to 72, got {}",
+                self.reader.fontsize
+            ));
+        }
+        if self.tts.voice.trim().is_empty() {
+            problems.push("tts.voice: must not be empty".to_string());
+        }
+        if !(1..=32).contains(&self.tts.chunks) {
+            problems.push(format!(
+                "tts.chunks: expected 1 to 32, got {}",
+                self.tts.chunks
+            ));
+        }
+        if self.cache.entries == 0 {
+            problems.push("cache.entries: must be at least 1".to_string());
+        }
+        if self.cache.prefetch_per_user == 0 {
+            problems.push("cache.prefetch_per_user: must be at least 1".to_string());
+        }
+        if let Some(dir) = &self.cache.dir
+            && dir.exists()
+            && !dir.is_dir()
+        {
+            problems.push(format!("cache.dir: {} is not a directory", dir.display()));
+        }
+        for scheme in &self.fetch.schemes {
+            if scheme != "http" && scheme != "https" {
+                problems.push(format!(
+                    "fetch.schemes: only http and https can be fetched, got {scheme:?}"
+                ));
+            }
+        }
+        if self.fetch.max_size < 1024 {
+            problems.push(format!(
+                "fetch.max_size: expected at least 1024, got {}",
+                self.fetch.max_size
+            ));
+        }
+        if self.tts.timeout == 0 {
+            problems.push("tts.timeout: must be at least 1 second".to_string());
+        }
+        if self.tts.listens_per_client == 0 {
+            problems.push("tts.listens_per_client: must be at least 1".to_string());
+        }
+        if self.tts.max_connections < self.tts.chunks {
+            // Each chunk of a chapter holds a connection while it's read out
+            problems.push(format!(
+                "tts.max_connections: must be at least tts.chunks ({}), got {}",
+                self.tts.chunks, self.tts.max_connections
+            ));
+        }
+        match self.auth.mode {
+            AuthMode::Token if self.auth.token.len() < 16 => {
+                problems.push("auth.token: token mode needs at least 16 characters".to_string());
+            }
+            AuthMode::Basic | AuthMode::Session => match &self.auth.htpasswd {
+                Some(file) if !file.is_file() => {
+                    problems.push(format!("auth.htpasswd: {} is not a file", file.display()));
+                }
+                None => problems
+                    .push("auth.htpasswd: basic and session modes need a users file".to_string()),
+                _ => {}
+            },
+            _ => {}
+        }
+        if self.auth.mode != AuthMode::Off && self.auth.session_days == 0 {
+            problems.push("auth.session_days: must be at least 1".to_string());
+        }
+        if self.health.tts_probe && self.health.tts_timeout == 0 {
+            problems.push("health.tts_timeout: must be at least 1 second".to_string());
+        }
+        if !problems.is_empty() {
+            bail!("invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            [server]
+            listen = "[::1]:8080"
+            [reader]
+            fontsize = 20
+            [rate_limit]
+            hosts = { "m.booklink.me" = "5:10:8" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.server.listen,
+            Listen::Tcp("[::1]:8080".parse().unwrap())
+        );
+        assert_eq!(config.reader.fontsize, 20);
+        assert_eq!(config.site.booksite, "https://m.booklink.me");
+        config.validate().unwrap();
+
+        assert!(Config::parse("[reader]\nfont = 20").is_err());
+        assert!(Config::parse("[server]\nlisten = \"9005\"").is_err());
+        let round = Config::parse(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(round.server.listen, config.server.listen);
+        assert_eq!(round.rate_limit.hosts, config.rate_limit.hosts);
+        assert_eq!(round.client.read_timeout, 30);
+    }
+
+    #[test]
+    fn test_upstreams() {
+        let config = Config::parse(
+            r#"
+            [[upstream]]
+            name = "booklink"
+            url = "https://m.booklink.me"
+            [[upstream]]
+            name = "other"
+            url = "https://example.com"
+            prefix = "/other"
+            replace = []
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let upstreams = config.upstreams();
+        assert_eq!(upstreams.len(), 2);
+        assert!(upstreams[1].replace.is_empty() && upstreams[1].dark_mode);
+        assert_eq!(
+            Config::default().upstreams()[0].url,
+            "https://m.booklink.me"
+        );
+
+        let mut config = config;
+        config.upstream[1].prefix = Some("other/".to_string());
+        config.upstream[1].name = "booklink".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("upstream[1].prefix") && err.contains("upstream[1].name"));
+    }
+
+    #[test]
+    fn test_search() {
+        let config = Config::parse(
+            r#"
+            [search]
+            mode = "sites"
+            [[upstream]]
+            name = "booklink"
+            url = "https://m.booklink.me"
+            [upstream.search]
+            url = "https://m.booklink.me/search.php?q={query}"
+            charset = "gbk"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let search = config.upstream[0].search.as_ref().unwrap();
+        assert_eq!(search.results, "a");
+        assert_eq!(config.search.engine, "https://kagi.com/search?q={query}");
+
+        let mut config = config;
+        config.upstream[0].search = None;
+        config.search.engine = "https://kagi.com/search".to_string();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("search.mode") && err.contains("search.engine"));
+        assert!(Config::parse("[search]\nmode = \"kagi\"").is_err());
+    }
+
+    #[test]
+    fn test_listen() {
+        assert_eq!(
+            "unix:/run/sr.sock".parse::<Listen>().unwrap(),
+            Listen::Unix("/run/sr.sock".into())
+        );
+        assert!("unix:".parse::<Listen>().is_err());
+        assert!("localhost".parse::<Listen>().is_err());
+        let l: Listen = "0.0.0.0:9005".parse().unwrap();
+        assert_eq!(l.to_string(), "0.0.0.0:9005");
+    }
+
+    #[test]
+    fn test_env_and_validate() {
+        let env: HashMap<&str, &str> = [
+            ("LISTEN_LOCAL", "1"),
+            ("LOCAL_PORT", "9100"),
+            ("FONTSIZE", "19"),
+            ("ACCESS_LOG", "json"),
+            ("AUTH_MODE", "token"),
+            ("AUTH_TOKEN", "short"),
+            ("TLS_CERT", "/nonexistent/cert.pem"),
+            ("READ_TIMEOUT", "0"),
+            ("RATE_LIMITS", "M.Booklink.me=5:10:8, example.com=fast"),
+            ("MAX_REQUEST_BODY", "65536"),
+            ("SHUTDOWN_GRACE", "7200"),
+            ("TRUSTED_PROXIES", "10.0.0.0/8, proxy.local"),
+        ]
+        .into();
+        let mut config = Config::default();
+        config
+            .apply_env(|n| env.get(n).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.server.listen.to_string(), "127.0.0.1:9100");
+        assert_eq!(config.reader.fontsize, 19);
+        assert_eq!(config.server.access_log, AccessLog::Json);
+        assert_eq!(config.auth.mode, AuthMode::Token);
+        assert_eq!(config.rate_limit.hosts["m.booklink.me"], "5:10:8");
+        assert_eq!(config.proxy.max_request_body, 65536);
+        assert_eq!(config.server.shutdown_grace, 7200);
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("auth.token"));
+        assert!(err.contains("tls: cert and key") && err.contains("client.read_timeout"));
+        assert!(err.contains("rate_limit: bad rate for example.com"));
+        assert!(err.contains("server.shutdown_grace"));
+        assert!(err.contains(r#"CIDR range, got "proxy.local""#));
+
+        let err = Config::default()
+            .apply_env(|n| (n == "LOCAL_PORT").then(|| "x".to_string()))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("LOCAL_PORT"));
+        let err = Config::default()
+            .apply_env(|n| (n == "CONNECT_TIMEOUT").then(|| "10s".to_string()))
+            .unwrap_err();
+        assert!(err.to_string().starts_with("CONNECT_TIMEOUT"));
+
+        let mut config = Config::default();
+        config.site.booksite = "booklink".to_string();
+        config.reader.fontsize = 2;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("site.booksite") && err.contains("reader.fontsize"));
+
+        let mut config = Config::default();
+        config.tts.max_connections = 5;
+        config.tts.timeout = 0;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("tts.max_connections") && err.contains("tts.timeout"));
+    }
+}
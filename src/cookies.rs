@@ -0,0 +1,445 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, bail};
+use log::debug;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc2822;
+use url::Url;
+
+use crate::config;
+use crate::sites::Site;
+
+// A parsed Set-Cookie header. Attributes keep their order and spelling so
+// the ones we don't touch come out as they went in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    attrs: Vec<(String, Option<String>)>,
+}
+
+impl SetCookie {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let attrs = parts
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(|a| match a.split_once('=') {
+                Some((k, v)) => (k.trim().to_string(), Some(v.trim().to_string())),
+                None => (a.to_string(), None),
+            })
+            .collect();
+        Some(SetCookie {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+            attrs,
+        })
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .rev()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    fn set(&mut self, name: &str, value: Option<String>) {
+        match self
+            .attrs
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        {
+            Some((_, v)) => *v = value,
+            None => self.attrs.push((name.to_string(), value)),
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.attrs.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    // When the cookie stops being valid: Max-Age wins over Expires
+    fn expires(&self) -> Option<SystemTime> {
+        if let Some(age) = self.attr("max-age").and_then(|v| v.parse::<i64>().ok()) {
+            return Some(if age <= 0 {
+                SystemTime::UNIX_EPOCH
+            } else {
+                SystemTime::now() + Duration::from_secs(age as u64)
+            });
+        }
+        let date = self.attr("expires")?.replace('-', " ");
+        OffsetDateTime::parse(&date, &Rfc2822)
+            .ok()
+            .map(SystemTime::from)
+    }
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        for (k, v) in &self.attrs {
+            match v {
+                Some(v) => write!(f, "; {k}={v}")?,
+                None => write!(f, "; {k}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether `host` is `domain` or one of its subdomains
+fn domain_match(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.');
+    host.eq_ignore_ascii_case(domain)
+        || (host.len() > domain.len()
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+            && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+fn without_port(host: &str) -> &str {
+    match host.parse::<hyper::http::uri::Authority>() {
+        Ok(a) => host.get(..a.host().len()).unwrap_or(host),
+        Err(_) => host,
+    }
+}
+
+// Map a Set-Cookie from `site` onto `host`, the address the reader uses.
+// Cookies for a domain that isn't the site's are dropped. Without TLS on
+// our side Secure is removed, or browsers would ignore the cookie.
+pub fn rewrite(set_cookie: &str, site: &Site, host: &str, secure: bool) -> Option<String> {
+    let mut cookie = SetCookie::parse(set_cookie)?;
+    let host = without_port(host).trim_matches(['[', ']']);
+    if let Some(domain) = cookie.attr("domain") {
+        let upstream = Url::parse(&site.url).ok()?;
+        let upstream = upstream.host_str().unwrap_or_default();
+        if !domain_match(upstream, domain) && !domain_match(domain, &site.cookie_domain) {
+            debug!("dropping cookie {} for domain {}", cookie.name, domain);
+            return None;
+        }
+        // Browsers won't take a Domain that is an address or a single label
+        if host.parse::<IpAddr>().is_ok() || !host.contains('.') {
+            cookie.remove("domain");
+        } else {
+            cookie.set("Domain", Some(host.to_string()));
+        }
+    }
+    if !site.prefix.is_empty() {
+        let path = cookie.attr("path").unwrap_or("/").trim_end_matches('/');
+        let path = format!("{}{}", site.prefix, path);
+        cookie.set("Path", Some(path));
+    }
+    if !secure && cookie.attr("secure").is_some() {
+        cookie.remove("secure");
+        // SameSite=None is only allowed on Secure cookies
+        if cookie
+            .attr("samesite")
+            .is_some_and(|v| v.eq_ignore_ascii_case("none"))
+        {
+            cookie.set("SameSite", Some("Lax".to_string()));
+        }
+    }
+    Some(cookie.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Stored {
+    name: String,
+    value: String,
+    domain: String,
+    // Sent to `domain` only, not its subdomains
+    host_only: bool,
+    path: String,
+    secure: bool,
+    // None for session cookies
+    expires: Option<SystemTime>,
+}
+
+impl Stored {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|e| e <= now)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        let path = url.path();
+        (if self.host_only {
+            host.eq_ignore_ascii_case(&self.domain)
+        } else {
+            domain_match(host, &self.domain)
+        }) && (path == self.path
+            || (path.starts_with(&self.path)
+                && (self.path.ends_with('/') || path[self.path.len()..].starts_with('/'))))
+            && (!self.secure || url.scheme() == "https")
+    }
+}
+
+// Cookies kept on the server for the pages we fetch ourselves, so sites
+// that need a login work in the reader
+#[derive(Debug, Default)]
+pub struct Jar(Mutex<Vec<Stored>>);
+
+impl Jar {
+    pub fn new(config: &config::Cookies) -> Result<Self> {
+        let jar = Jar::default();
+        if let Some(path) = &config.file {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let cookies =
+                parse_netscape(&text).with_context(|| format!("bad cookies {}", path.display()))?;
+            *jar.0.lock().unwrap() = cookies;
+        }
+        Ok(jar)
+    }
+
+    // Keep a cookie a page at `url` set
+    pub fn store(&self, url: &Url, set_cookie: &str) {
+        let Some(cookie) = SetCookie::parse(set_cookie) else {
+            return;
+        };
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        let (domain, host_only) = match cookie.attr("domain").filter(|d| !d.is_empty()) {
+            Some(d) if domain_match(&host, d) => (d.trim_start_matches('.').to_lowercase(), false),
+            Some(d) => {
+                debug!("ignoring cookie {} from {} for {}", cookie.name, host, d);
+                return;
+            }
+            None => (host, true),
+        };
+        let path = match cookie.attr("path") {
+            Some(p) if p.starts_with('/') => p.to_string(),
+            // The directory of the page
+            _ => match url.path().rfind('/') {
+                Some(0) | None => "/".to_string(),
+                Some(i) => url.path()[..i].to_string(),
+            },
+        };
+        let stored = Stored {
+            secure: cookie.attr("secure").is_some(),
+            expires: cookie.expires(),
+            name: cookie.name,
+            value: cookie.value,
+            domain,
+            host_only,
+            path,
+        };
+        let mut cookies = self.0.lock().unwrap();
+        cookies.retain(|c| {
+            c.name != stored.name || c.domain != stored.domain || c.path != stored.path
+        });
+        if !stored.expired(SystemTime::now()) {
+            cookies.push(stored);
+        }
+    }
+
+    // The Cookie header for a request to `url`
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let now = SystemTime::now();
+        let mut cookies = self.0.lock().unwrap();
+        cookies.retain(|c| !c.expired(now));
+        let mut matched: Vec<&Stored> = cookies.iter().filter(|c| c.matches(url)).collect();
+        if matched.is_empty() {
+            return None;
+        }
+        // Longer paths first
+        matched.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+        Some(
+            matched
+                .iter()
+                .map(|c| format!("{}={}", c.name, c.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        )
+    }
+
+    // The cookies as a cookies.txt, session cookies included so a restart
+    // doesn't log us out
+    pub fn export(&self) -> String {
+        let now = SystemTime::now();
+        let mut out = String::from("# Netscape HTTP Cookie File\n");
+        for c in self.0.lock().unwrap().iter().filter(|c| !c.expired(now)) {
+            let expires = c
+                .expires
+                .and_then(|e| e.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0, |e| e.as_secs());
+            let flag = |b: bool| if b { "TRUE" } else { "FALSE" };
+            out.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if c.host_only { "" } else { "." },
+                c.domain,
+                flag(!c.host_only),
+                c.path,
+                flag(c.secure),
+                expires,
+                c.name,
+                c.value
+            ));
+        }
+        out
+    }
+
+    // Add the cookies in a cookies.txt, replacing those we have with the
+    // same name, domain and path. Returns how many there were.
+    pub fn import(&self, text: &str) -> Result<usize> {
+        let imported = parse_netscape(text)?;
+        let n = imported.len();
+        let mut cookies = self.0.lock().unwrap();
+        cookies.retain(|c| {
+            !imported
+                .iter()
+                .any(|i| c.name == i.name && c.domain == i.domain && c.path == i.path)
+        });
+        cookies.extend(imported);
+        Ok(n)
+    }
+}
+
+// Read a cookies.txt as exported by browsers and curl
+fn parse_netscape(text: &str) -> Result<Vec<Stored>> {
+    let mut cookies = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        // curl marks HttpOnly cookies this way
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+            bail!("line {}: expected 7 tab separated fields", i + 1);
+        };
+        let expires: u64 = expires
+            .parse()
+            .with_context(|| format!("line {}: bad expiry {expires:?}", i + 1))?;
+        cookies.push(Stored {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: domain.trim_start_matches('.').to_ascii_lowercase(),
+            host_only: subdomains != "TRUE",
+            path: path.to_string(),
+            secure: secure == "TRUE",
+            expires: (expires != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(expires)),
+        });
+    }
+    Ok(cookies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Upstream;
+    use crate::sites::Sites;
+
+    #[test]
+    fn test_rewrite() {
+        let mut ex = Upstream::from_booksite("https://m.example.com");
+        ex.name = "example".to_string();
+        ex.prefix = Some("/ex".to_string());
+        let sites = Sites::new(vec![Upstream::from_booksite("https://m.booklink.me"), ex]).unwrap();
+        let booklink = sites.select(None, "/");
+        let example = sites.select(None, "/ex/");
+
+        assert_eq!(
+            rewrite(
+                "sid=1; Domain=.booklink.me; Path=/; Secure; SameSite=None; HttpOnly",
+                booklink,
+                "reader.example.org:8443",
+                false
+            )
+            .as_deref(),
+            Some("sid=1; Domain=reader.example.org; Path=/; SameSite=Lax; HttpOnly")
+        );
+        assert_eq!(
+            rewrite(
+                "sid=1; domain=m.booklink.me; Secure",
+                booklink,
+                "127.0.0.1:9005",
+                true
+            )
+            .as_deref(),
+            Some("sid=1; Secure")
+        );
+        assert_eq!(
+            rewrite("t=x; Domain=tracker.com", booklink, "reader.org", true),
+            None
+        );
+        assert_eq!(
+            rewrite("a=b; Path=/book/", example, "reader.org", true).as_deref(),
+            Some("a=b; Path=/ex/book")
+        );
+        assert_eq!(
+            rewrite("a=b", example, "reader.org", true).as_deref(),
+            Some("a=b; Path=/ex")
+        );
+        assert_eq!(rewrite("garbage", booklink, "reader.org", true), None);
+    }
+
+    #[test]
+    fn test_jar() {
+        let jar = Jar::default();
+        let page = Url::parse("https://m.booklink.me/user/login.php").unwrap();
+        jar.store(&page, "sid=1; Domain=booklink.me; Path=/");
+        jar.store(&page, "tmp=2; Max-Age=3600");
+        jar.store(&page, "old=3; Expires=Wed, 21-Oct-2015 07:28:00 GMT");
+        jar.store(&page, "evil=4; Domain=example.com");
+        let url = |u: &str| Url::parse(u).unwrap();
+        assert_eq!(
+            jar.header(&url("https://m.booklink.me/user/x")).as_deref(),
+            Some("tmp=2; sid=1")
+        );
+        assert_eq!(
+            jar.header(&url("https://www.booklink.me/")).as_deref(),
+            Some("sid=1")
+        );
+        assert_eq!(jar.header(&url("https://example.com/")), None);
+        // A cookie can be replaced and deleted
+        jar.store(&page, "sid=5; Domain=booklink.me; Path=/");
+        assert_eq!(
+            jar.header(&url("http://booklink.me/")).as_deref(),
+            Some("sid=5")
+        );
+        jar.store(&page, "sid=; Domain=booklink.me; Path=/; Max-Age=0");
+        assert_eq!(jar.header(&url("http://booklink.me/")), None);
+    }
+
+    #[test]
+    fn test_netscape() {
+        let cookies = parse_netscape(
+            "# Netscape HTTP Cookie File\n\
+             .booklink.me\tTRUE\t/\tTRUE\t0\tsid\tabc\n\
+             #HttpOnly_m.booklink.me\tFALSE\t/user\tFALSE\t4102444800\ttoken\txyz\n",
+        )
+        .unwrap();
+        let jar = Jar(Mutex::new(cookies));
+        let url = |u: &str| Url::parse(u).unwrap();
+        assert_eq!(
+            jar.header(&url("https://m.booklink.me/user/1")).as_deref(),
+            Some("token=xyz; sid=abc")
+        );
+        assert_eq!(
+            jar.header(&url("http://m.booklink.me/user/1")).as_deref(),
+            Some("token=xyz")
+        );
+        assert!(parse_netscape("booklink.me\tTRUE\t/").is_err());
+
+        // What is saved on shutdown comes back the same
+        let saved = Jar::default();
+        assert_eq!(saved.import(&jar.export()).unwrap(), 2);
+        assert_eq!(*saved.0.lock().unwrap(), *jar.0.lock().unwrap());
+        saved
+            .import(".booklink.me\tTRUE\t/\tTRUE\t0\tsid\tnew\n")
+            .unwrap();
+        assert_eq!(
+            saved.header(&url("https://m.booklink.me/")).as_deref(),
+            Some("sid=new")
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::time::Duration;
+
+use hyper::{Response, StatusCode};
+use log::{error, warn};
+
+use crate::assets;
+use crate::body::{self, Body};
+use crate::utils::escape_html;
+
+// Failures we can explain to the reader, as opposed to plain bugs
+#[derive(Debug)]
+pub enum AppError {
+    UpstreamTimeout { url: String },
+    UpstreamUnreachable { url: String, reason: String },
+    UpstreamStatus { url: String, status: StatusCode },
+    Decode { url: String, reason: String },
+    Extract { url: String, reason: String },
+    Tts { reason: String },
+    // The client refused every coding we could send the body in
+    NotAcceptable { accept_encoding: String },
+    // The url is off limits under [fetch]
+    Forbidden { url: String, reason: String },
+    // A client is over its speech synthesis limits
+    TooManyRequests { retry_after: Duration },
+    // The body of a request to pass upstream was over proxy.max_request_body
+    RequestTooLarge { max: usize },
+    // The client took longer than the read timeout to send the body
+    RequestTimeout,
+}
+
+impl AppError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::UpstreamTimeout { .. } => "upstream_timeout",
+            AppError::UpstreamUnreachable { .. } => "upstream_unreachable",
+            AppError::UpstreamStatus { .. } => "upstream_status",
+            AppError::Decode { .. } => "decode",
+            AppError::Extract { .. } => "extract",
+            AppError::Tts { .. } => "tts",
+            AppError::NotAcceptable { .. } => "not_acceptable",
+            AppError::Forbidden { .. } => "forbidden",
+            AppError::TooManyRequests { .. } => "rate_limited",
+            AppError::RequestTooLarge { .. } => "request_too_large",
+            AppError::RequestTimeout => "request_timeout",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::UpstreamTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            AppError::UpstreamStatus { status, .. }
+                if *status == StatusCode::NOT_FOUND || *status == StatusCode::GONE =>
+            {
+                StatusCode::NOT_FOUND
+            }
+            AppError::Extract { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::NotAcceptable { .. } => StatusCode::NOT_ACCEPTABLE,
+            AppError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::RequestTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            AppError::UpstreamUnreachable { .. }
+            | AppError::UpstreamStatus { .. }
+            | AppError::Decode { .. }
+            | AppError::Tts { .. } => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            AppError::UpstreamTimeout { url }
+            | AppError::UpstreamUnreachable { url, .. }
+            | AppError::UpstreamStatus { url, .. }
+            | AppError::Decode { url, .. }
+            | AppError::Extract { url, .. }
+            | AppError::Forbidden { url, .. } => Some(url),
+            AppError::Tts { .. }
+            | AppError::NotAcceptable { .. }
+            | AppError::TooManyRequests { .. }
+            | AppError::RequestTooLarge { .. }
+            | AppError::RequestTimeout => None,
+        }
+    }
+
+    // A message for the error page, in the reader's language
+    fn message(&self) -> &'static str {
+        match self {
+            AppError::UpstreamTimeout { .. } => "源站响应超时",
+            AppError::UpstreamUnreachable { .. } => "无法连接源站",
+            AppError::UpstreamStatus { .. } => "源站返回错误",
+            AppError::Decode { .. } => "无法解码源站内容",
+            AppError::Extract { .. } => "无法提取正文",
+            AppError::Tts { .. } => "语音合成失败",
+            AppError::NotAcceptable { .. } => "浏览器不接受可用的内容编码",
+            AppError::Forbidden { .. } => "不允许访问该网址",
+            AppError::TooManyRequests { .. } => "朗读请求过于频繁，请稍后再试",
+            AppError::RequestTooLarge { .. } => "提交的内容过大",
+            AppError::RequestTimeout => "提交内容超时",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::UpstreamTimeout { url } => write!(f, "timed out fetching {url}"),
+            AppError::UpstreamUnreachable { url, reason } => {
+                write!(f, "failed to fetch {url}: {reason}")
+            }
+            AppError::UpstreamStatus { url, status } => write!(f, "{url} returned {status}"),
+            AppError::Decode { url, reason } => write!(f, "failed to decode {url}: {reason}"),
+            AppError::Extract { url, reason } => {
+                write!(f, "failed to extract content from {url}: {reason}")
+            }
+            AppError::Tts { reason } => write!(f, "speech synthesis failed: {reason}"),
+            AppError::Forbidden { url, reason } => write!(f, "refused to fetch {url}: {reason}"),
+            AppError::TooManyRequests { retry_after } => {
+                write!(f, "too many speech requests, retry after {retry_after:?}")
+            }
+            AppError::RequestTooLarge { max } => {
+                write!(f, "request body is larger than {max} bytes")
+            }
+            AppError::RequestTimeout => write!(f, "timed out reading the request body"),
+            AppError::NotAcceptable { accept_encoding } => {
+                write!(
+                    f,
+                    "no acceptable coding in Accept-Encoding: {accept_encoding}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+// An error and its sources on one line, e.g. "client error (Connect): Connection refused"
+pub fn describe(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut s = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        s.push_str(": ");
+        s.push_str(&e.to_string());
+        source = e.source();
+    }
+    s
+}
+
+// Log a failed request and turn it into a reader themed error page.
+// `retry` is the link that repeats the request, `origin` the page the
+// reader asked for.
+pub fn error_page(
+    err: &anyhow::Error,
+    fontsize: &str,
+    retry: &str,
+    origin: Option<&str>,
+) -> Response<Body> {
+    let app = err.chain().find_map(|e| e.downcast_ref::<AppError>());
+    let (status, kind, message) = match app {
+        Some(e) => (e.status(), e.kind(), e.message()),
+        None => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "服务器内部错误",
+        ),
+    };
+    let upstream = app.and_then(AppError::url).unwrap_or("-");
+    if app.is_some() {
+        warn!(
+            "request failed: kind={} status={} upstream={} retry={} error={:#}",
+            kind,
+            status.as_u16(),
+            upstream,
+            retry,
+            err
+        );
+    } else {
+        error!(
+            "request failed: kind={} status={} retry={} error={:?}",
+            kind,
+            status.as_u16(),
+            retry,
+            err
+        );
+    }
+    // Only web pages get a link; a javascript: url would run on click
+    let origin = origin
+        .filter(|o| url::Url::parse(o).is_ok_and(|u| matches!(u.scheme(), "http" | "https")))
+        .map(|o| format!(r#"<p>原网页：<a href="{0}">{0}</a></p>"#, escape_html(o)))
+        .unwrap_or_default();
+    let html = format!(
+        r#"<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1.0" /><title>{code} {message}</title>{stylesheet}<style> p{{font-size:{fontsize}px;}}</style></head><body><h3>{message}</h3><p>{code} {reason}</p><p>{detail}</p>{origin}<p><a href="{retry}">重试</a></p>
+{theme}
+</body></html>"#,
+        code = status.as_u16(),
+        fontsize = fontsize,
+        reason = status.canonical_reason().unwrap_or_default(),
+        message = message,
+        detail = escape_html(&format!("{err}")),
+        origin = origin,
+        retry = escape_html(retry),
+        stylesheet = assets::stylesheet(),
+        theme = assets::script("theme.js"),
+    );
+    let mut resp = Response::new(body::full(html));
+    *resp.status_mut() = status;
+    if let Some(AppError::TooManyRequests { retry_after }) = app {
+        resp.headers_mut()
+            .insert(hyper::header::RETRY_AFTER, retry_after.as_secs().into());
+    }
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    async fn page(origin: &str) -> String {
+        let err = anyhow::anyhow!("bug");
+        let resp = error_page(&err, "20", "/?dest=x", Some(origin));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_origin_link() {
+        assert!(
+            page("https://m.booklink.me/book-1-1.html")
+                .await
+                .contains("原网页")
+        );
+        assert!(!page("javascript:alert(1)").await.contains("原网页"));
+        assert!(!page("data:text/html,x").await.contains("原网页"));
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::Write;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::error::AppError;
+use crate::utils::escape_html;
+use crate::{AppContext, Chapter};
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    Txt,
+    Md,
+    Html,
+    Json,
+}
+
+// Exit codes of `simplereading fetch`, by what went wrong
+pub const EXIT_HELP: &str = "Exit codes: 0 ok, 1 other errors, 2 bad usage or config, \
+3 upstream unreachable or timed out, 4 upstream returned an error status, \
+5 page could not be decoded or had no text, 6 url refused by [fetch]";
+
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    if err.is::<url::ParseError>() {
+        return 2;
+    }
+    match err.chain().find_map(|e| e.downcast_ref::<AppError>()) {
+        Some(AppError::UpstreamTimeout { .. } | AppError::UpstreamUnreachable { .. }) => 3,
+        Some(AppError::UpstreamStatus { .. }) => 4,
+        Some(AppError::Decode { .. } | AppError::Extract { .. }) => 5,
+        Some(AppError::Forbidden { .. }) => 6,
+        _ => 1,
+    }
+}
+
+// Fetch the chapter at `url` with all its pages, print it to stdout and
+// return the exit code
+pub async fn run(context: &AppContext, url: &str, format: Format) -> i32 {
+    match fetch(context, url, format).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("simplereading: {e:#}");
+            exit_code(&e)
+        }
+    }
+}
+
+async fn fetch(context: &AppContext, url: &str, format: Format) -> Result<()> {
+    let chapter = crate::get_all_txt(context, url.to_string()).await?;
+    if paragraphs(&chapter.text).is_empty() {
+        return Err(AppError::Extract {
+            url: url.to_string(),
+            reason: "no text found".to_string(),
+        }
+        .into());
+    }
+    let mut out = std::io::stdout().lock();
+    out.write_all(render(&chapter, url, format).as_bytes())?;
+    out.flush()?;
+    Ok(())
+}
+
+fn render(chapter: &Chapter, url: &str, format: Format) -> String {
+    let paragraphs = paragraphs(&chapter.text);
+    match format {
+        Format::Txt => {
+            let mut out = format!("{}\n\n", chapter.title);
+            for p in &paragraphs {
+                out.push_str(p);
+                out.push_str("\n\n");
+            }
+            out.truncate(out.trim_end().len());
+            out.push('\n');
+            out
+        }
+        Format::Md => {
+            let mut out = format!("# {}\n", markdown(&chapter.title));
+            for p in &paragraphs {
+                out.push('\n');
+                out.push_str(&markdown(p));
+                out.push('\n');
+            }
+            out
+        }
+        Format::Html => format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head><body><h3>{title}</h3>\n{text}\n</body></html>\n",
+            title = escape_html(&chapter.title),
+            text = paragraphs
+                .iter()
+                .map(|p| format!("<p>{}</p>", line_breaks(&escape_html(p))))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        Format::Json => {
+            let value = json!({
+                "url": url,
+                "title": chapter.title,
+                "next": chapter.next,
+                "paragraphs": paragraphs.iter().map(|p| line_breaks(p)).collect::<Vec<_>>(),
+            });
+            format!("{value:#}\n")
+        }
+    }
+}
+
+// The chapter text is <p> paragraphs of escaped text, with <br> for line
+// breaks, which come out as \n
+fn paragraphs(text: &str) -> Vec<String> {
+    text.split("<p>")
+        .map(|p| unescape(strip_tags(p).trim()))
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+fn strip_tags(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut tag: Option<String> = None;
+    for c in s.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (Some(name), '>') => {
+                let name = name.trim_end_matches('/').trim().to_ascii_lowercase();
+                if name == "br" {
+                    out.push('\n');
+                }
+                tag = None;
+            }
+            (Some(name), c) => name.push(c),
+            (None, c) => out.push(c),
+        }
+    }
+    out
+}
+
+// Line breaks as <br> again, for the formats that are html
+fn line_breaks(s: &str) -> String {
+    s.replace('\n', "<br>")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn markdown(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let chapter = Chapter {
+            title: "第一章 *开始*".to_string(),
+            text: "<p>  第一段 &amp; 更多</p>\n<p></p><p>第二段<br/>续<BR></p>".to_string(),
+            next: Some("https://m.booklink.me/book-1-2.html".to_string()),
+        };
+        let url = "https://m.booklink.me/book-1-1.html";
+        assert_eq!(
+            render(&chapter, url, Format::Txt),
+            "第一章 *开始*\n\n第一段 & 更多\n\n第二段\n续\n"
+        );
+        assert_eq!(
+            render(&chapter, url, Format::Md),
+            "# 第一章 \\*开始\\*\n\n第一段 & 更多\n\n第二段\n续\n"
+        );
+        assert!(
+            render(&chapter, url, Format::Html)
+                .contains("<p>第一段 &amp; 更多</p>\n<p>第二段<br>续</p>")
+        );
+        let value: serde_json::Value =
+            serde_json::from_str(&render(&chapter, url, Format::Json)).unwrap();
+        assert_eq!(value["paragraphs"][1], "第二段<br>续");
+        assert_eq!(value["next"], "https://m.booklink.me/book-1-2.html");
+
+        assert_eq!(
+            exit_code(
+                &AppError::Forbidden {
+                    url: url.to_string(),
+                    reason: String::new()
+                }
+                .into()
+            ),
+            6
+        );
+        assert_eq!(exit_code(&url::Url::parse("x").unwrap_err().into()), 2);
+        assert_eq!(exit_code(&anyhow::anyhow!("bug")), 1);
+    }
+}
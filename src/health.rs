@@ -0,0 +1,192 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use hyper::header::{self, HeaderValue};
+use hyper::{Response, StatusCode};
+use log::warn;
+use serde_json::{Map, Value, json};
+
+use crate::body::{self, Body};
+use crate::connect::Connector;
+use crate::{config, utils};
+
+// How long a TTS probe result is reused, so frequent polls don't all hit
+// the speech service
+const TTS_PROBE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+enum Status {
+    Ok,
+    Skipped(&'static str),
+    Failed(String),
+}
+
+impl Status {
+    fn json(&self, elapsed: Duration) -> Value {
+        let ms = elapsed.as_millis() as u64;
+        match self {
+            Status::Ok => json!({ "status": "ok", "ms": ms }),
+            Status::Skipped(why) => json!({ "status": "skipped", "reason": why }),
+            Status::Failed(error) => json!({ "status": "fail", "error": error, "ms": ms }),
+        }
+    }
+}
+
+// Liveness and readiness for process supervisors
+pub struct Health {
+    config: config::Config,
+    started: Instant,
+    tts: Mutex<Option<(Instant, Status, Duration)>>,
+}
+
+impl std::fmt::Debug for Health {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Health")
+            .field("tts_probe", &self.config.health.tts_probe)
+            .field("tts_timeout", &self.config.health.tts_timeout)
+            .finish()
+    }
+}
+
+impl Health {
+    pub fn new(config: &config::Config) -> Self {
+        Health {
+            config: config.clone(),
+            started: Instant::now(),
+            tts: Mutex::new(None),
+        }
+    }
+
+    // /healthz: the process is up and answering
+    pub fn live(&self) -> Result<Response<Body>> {
+        let uptime = self.started.elapsed().as_secs();
+        respond(StatusCode::OK, json!({ "status": "ok", "uptime": uptime }))
+    }
+
+    // /readyz: the files we depend on are still in place and, if asked
+    // for, the speech service can be reached
+    pub async fn ready(&self, connector: &Connector) -> Result<Response<Body>> {
+        let mut checks = Map::new();
+        let mut ok = true;
+        let mut add = |name: &str, status: Status, elapsed: Duration| {
+            ok &= !matches!(status, Status::Failed(_));
+            checks.insert(name.to_string(), status.json(elapsed));
+        };
+
+        let start = Instant::now();
+        let status = match self.config.validate() {
+            Ok(()) => Status::Ok,
+            Err(e) => Status::Failed(format!("{e:#}")),
+        };
+        add("config", status, start.elapsed());
+
+        let start = Instant::now();
+        let status = match &self.config.cache.dir {
+            Some(dir) => writable(dir),
+            None => Status::Skipped("cache.dir is not set"),
+        };
+        add("cache_dir", status, start.elapsed());
+
+        let (status, elapsed) = self.tts(connector).await;
+        add("tts", status, elapsed);
+
+        let (status, text) = if ok {
+            (StatusCode::OK, "ok")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "fail")
+        };
+        respond(status, json!({ "status": text, "checks": checks }))
+    }
+
+    async fn tts(&self, connector: &Connector) -> (Status, Duration) {
+        if !self.config.health.tts_probe {
+            return (Status::Skipped("health.tts_probe is off"), Duration::ZERO);
+        }
+        if let Some((at, status, elapsed)) = self.tts.lock().unwrap().clone()
+            && at.elapsed() < TTS_PROBE_TTL
+        {
+            return (status, elapsed);
+        }
+        let start = Instant::now();
+        let timeout = Duration::from_secs(self.config.health.tts_timeout);
+        let id = uuid::Uuid::new_v4().as_simple().to_string().to_uppercase();
+        let status = match tokio::time::timeout(timeout, utils::tts_connect(connector, &id)).await {
+            Ok(Ok(mut ws)) => {
+                let _ = ws.close(None).await;
+                Status::Ok
+            }
+            Ok(Err(e)) => Status::Failed(format!("{e:#}")),
+            Err(_) => Status::Failed(format!("no connection within {timeout:?}")),
+        };
+        if let Status::Failed(e) = &status {
+            warn!("TTS probe failed: {}", e);
+        }
+        let elapsed = start.elapsed();
+        *self.tts.lock().unwrap() = Some((Instant::now(), status.clone(), elapsed));
+        (status, elapsed)
+    }
+}
+
+// Whether we can create files in `dir`
+fn writable(dir: &Path) -> Status {
+    let probe = dir.join(format!(".readyz-{}", std::process::id()));
+    let result = std::fs::write(&probe, b"").and_then(|()| std::fs::remove_file(&probe));
+    match result {
+        Ok(()) => Status::Ok,
+        Err(e) => Status::Failed(format!("{}: {e}", dir.display())),
+    }
+}
+
+fn respond(status: StatusCode, value: Value) -> Result<Response<Body>> {
+    let mut resp = Response::new(body::full(value.to_string()));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    resp.headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn json(resp: Response<Body>) -> Value {
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ready() {
+        let connector = Connector::new(Duration::from_secs(1), Duration::from_secs(60)).unwrap();
+        let dir = std::env::temp_dir().join(format!("simplereading-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = config::Config::default();
+        config.cache.dir = Some(dir.clone());
+        let health = Health::new(&config);
+
+        let resp = health.ready(&connector).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let value = json(resp).await;
+        assert_eq!(value["status"], "ok");
+        assert_eq!(value["checks"]["config"]["status"], "ok");
+        assert_eq!(value["checks"]["cache_dir"]["status"], "ok");
+        assert_eq!(value["checks"]["tts"]["status"], "skipped");
+
+        // The cache directory went away under us
+        std::fs::remove_dir(&dir).unwrap();
+        let resp = health.ready(&connector).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let value = json(resp).await;
+        assert_eq!(value["status"], "fail");
+        assert_eq!(value["checks"]["cache_dir"]["status"], "fail");
+
+        let value = json(health.live().unwrap()).await;
+        assert_eq!(value["status"], "ok");
+    }
+}
@@ -0,0 +1,679 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Response, StatusCode};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc2822;
+
+use crate::body::{self, Body};
+use crate::config;
+
+// Responses cached without explicit freshness get a tenth of their age,
+// up to a day
+const HEURISTIC_MAX: Duration = Duration::from_secs(24 * 3600);
+
+// Statuses we keep, the common ones a shared cache may store
+const STORABLE: [StatusCode; 6] = [
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::PERMANENT_REDIRECT,
+    StatusCode::NOT_FOUND,
+    StatusCode::GONE,
+];
+
+// Headers a 304 may not change on the stored response
+const KEEP_ON_REFRESH: [HeaderName; 3] = [
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_TYPE,
+];
+
+fn http_date(v: &HeaderValue) -> Option<SystemTime> {
+    let v = v.to_str().ok()?;
+    OffsetDateTime::parse(v.trim(), &Rfc2822)
+        .ok()
+        .map(SystemTime::from)
+}
+
+// Cache-Control directives, names lowercased
+fn directives(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|d| {
+            let d = d.trim();
+            if d.is_empty() {
+                return None;
+            }
+            Some(match d.split_once('=') {
+                Some((k, v)) => (
+                    k.trim().to_ascii_lowercase(),
+                    Some(v.trim().trim_matches('"').to_string()),
+                ),
+                None => (d.to_ascii_lowercase(), None),
+            })
+        })
+        .collect()
+}
+
+fn has(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(k, _)| k == name)
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(k, _)| k == name)
+        .and_then(|(_, v)| v.as_deref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+// Whether the client asked us to check with the upstream, as on reload
+pub fn wants_revalidation(req: &HeaderMap) -> bool {
+    let d = directives(req);
+    has(&d, "no-cache") || seconds(&d, "max-age") == Some(Duration::ZERO)
+}
+
+// Request headers a cached response was chosen by: those it names in Vary,
+// and Accept-Encoding since the body is kept as the upstream encoded it
+fn vary_names(resp: &HeaderMap) -> Vec<HeaderName> {
+    let mut names = vec![header::ACCEPT_ENCODING];
+    for v in resp.get_all(header::VARY).iter() {
+        for name in v.to_str().unwrap_or_default().split(',') {
+            if let Ok(name) = HeaderName::from_bytes(name.trim().as_bytes())
+                && !names.contains(&name)
+            {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+fn request_values(req: &HeaderMap, names: &[HeaderName]) -> Vec<(HeaderName, Option<HeaderValue>)> {
+    names
+        .iter()
+        .map(|n| (n.clone(), req.get(n).cloned()))
+        .collect()
+}
+
+// Compare entity tags, ignoring weakness
+fn etag_match(a: &str, b: &str) -> bool {
+    let a = a.trim();
+    let b = b.trim();
+    a.strip_prefix("W/").unwrap_or(a) == b.strip_prefix("W/").unwrap_or(b)
+}
+
+// Whether the client already has this response, by If-None-Match or
+// failing that If-Modified-Since
+pub fn not_modified(req: &HeaderMap, resp: &HeaderMap) -> bool {
+    if let Some(inm) = req.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        let Some(etag) = resp.get(header::ETAG).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        return inm.trim() == "*" || inm.split(',').any(|t| etag_match(t, etag));
+    }
+    match (
+        req.get(header::IF_MODIFIED_SINCE).and_then(http_date),
+        resp.get(header::LAST_MODIFIED).and_then(http_date),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+// The 304 for a response the client has: its validators and caching headers
+pub fn not_modified_response(resp: &HeaderMap) -> Response<Body> {
+    let mut r = Response::new(body::full(Bytes::new()));
+    *r.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in [
+        header::ETAG,
+        header::LAST_MODIFIED,
+        header::CACHE_CONTROL,
+        header::EXPIRES,
+        header::VARY,
+        header::DATE,
+        header::AGE,
+        header::CONTENT_LOCATION,
+    ] {
+        for v in resp.get_all(&name) {
+            r.headers_mut().append(name.clone(), v.clone());
+        }
+    }
+    r
+}
+
+// A stored upstream response
+#[derive(Debug)]
+pub struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    // When we received it, and how old it already was then
+    stored: SystemTime,
+    age: Duration,
+}
+
+impl Entry {
+    fn new(status: StatusCode, headers: HeaderMap, body: Bytes, req: &HeaderMap) -> Self {
+        let vary = request_values(req, &vary_names(&headers));
+        let age = headers
+            .get(header::AGE)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        Entry {
+            status,
+            headers,
+            body,
+            vary,
+            stored: SystemTime::now(),
+            age,
+        }
+    }
+
+    fn current_age(&self) -> Duration {
+        self.age + self.stored.elapsed().unwrap_or_default()
+    }
+
+    // How long the response stays fresh
+    fn lifetime(&self) -> Duration {
+        let d = directives(&self.headers);
+        if has(&d, "no-cache") {
+            return Duration::ZERO;
+        }
+        if let Some(s) = seconds(&d, "s-maxage").or_else(|| seconds(&d, "max-age")) {
+            return s;
+        }
+        let date = self.headers.get(header::DATE).and_then(http_date);
+        let base = date.unwrap_or(self.stored);
+        if self.headers.contains_key(header::EXPIRES) {
+            // An invalid Expires means already expired
+            return self
+                .headers
+                .get(header::EXPIRES)
+                .and_then(http_date)
+                .and_then(|e| e.duration_since(base).ok())
+                .unwrap_or_default();
+        }
+        self.headers
+            .get(header::LAST_MODIFIED)
+            .and_then(http_date)
+            .and_then(|m| base.duration_since(m).ok())
+            .map(|age| (age / 10).min(HEURISTIC_MAX))
+            .unwrap_or_default()
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.current_age() < self.lifetime()
+    }
+
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(k, v)| k.as_str().len() + v.len())
+                .sum::<usize>()
+    }
+
+    // Make the upstream request conditional on this response
+    pub fn add_validators(&self, req: &mut HeaderMap) {
+        if let Some(etag) = self.headers.get(header::ETAG) {
+            req.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(modified) = self.headers.get(header::LAST_MODIFIED) {
+            req.insert(header::IF_MODIFIED_SINCE, modified.clone());
+        }
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        let mut r = Response::new(body::full(self.body.clone()));
+        *r.status_mut() = self.status;
+        *r.headers_mut() = self.headers.clone();
+        r.headers_mut()
+            .insert(header::AGE, HeaderValue::from(self.current_age().as_secs()));
+        r
+    }
+}
+
+// An entry as saved to disk, one JSON object a line
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    vary: Vec<(String, Option<String>)>,
+    // Unix seconds
+    stored: u64,
+    age: u64,
+    body: String,
+}
+
+impl Saved {
+    fn new(url: &str, entry: &Entry) -> Self {
+        let text = |v: &HeaderValue| String::from_utf8_lossy(v.as_bytes()).into_owned();
+        Saved {
+            url: url.to_string(),
+            status: entry.status.as_u16(),
+            headers: entry
+                .headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), text(v)))
+                .collect(),
+            vary: entry
+                .vary
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.as_ref().map(text)))
+                .collect(),
+            stored: entry
+                .stored
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            age: entry.age.as_secs(),
+            body: STANDARD.encode(&entry.body),
+        }
+    }
+
+    fn entry(self) -> Option<(String, Entry)> {
+        let mut headers = HeaderMap::new();
+        for (k, v) in self.headers {
+            headers.append(HeaderName::from_bytes(k.as_bytes()).ok()?, v.parse().ok()?);
+        }
+        let mut vary = Vec::new();
+        for (k, v) in self.vary {
+            let v = match v {
+                Some(v) => Some(v.parse().ok()?),
+                None => None,
+            };
+            vary.push((HeaderName::from_bytes(k.as_bytes()).ok()?, v));
+        }
+        let entry = Entry {
+            status: StatusCode::from_u16(self.status).ok()?,
+            headers,
+            body: STANDARD.decode(self.body).ok()?.into(),
+            vary,
+            stored: SystemTime::UNIX_EPOCH + Duration::from_secs(self.stored),
+            age: Duration::from_secs(self.age),
+        };
+        Some((self.url, entry))
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    // Entries by url, with when they were last used
+    by_url: HashMap<String, (u64, Arc<Entry>)>,
+    // Urls from least to most recently used
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+    // Bytes held by all entries
+    size: usize,
+}
+
+impl Entries {
+    fn touch(&mut self, url: &str) {
+        self.clock += 1;
+        if let Some((used, _)) = self.by_url.get_mut(url) {
+            self.by_use.remove(used);
+            *used = self.clock;
+            self.by_use.insert(self.clock, url.to_string());
+        }
+    }
+
+    fn remove(&mut self, url: &str) {
+        if let Some((used, entry)) = self.by_url.remove(url) {
+            self.by_use.remove(&used);
+            self.size -= entry.size();
+        }
+    }
+}
+
+// A shared in-memory cache of upstream responses, bounded in bytes and
+// evicting the least recently used
+pub struct HttpCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    max_entry: usize,
+}
+
+impl std::fmt::Debug for HttpCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpCache")
+            .field("capacity", &self.capacity)
+            .field("max_entry", &self.max_entry)
+            .field("entries", &self.entries.lock().unwrap().by_url.len())
+            .finish()
+    }
+}
+
+impl HttpCache {
+    pub fn new(config: &config::Cache) -> Self {
+        HttpCache {
+            entries: Mutex::default(),
+            capacity: config.http_size,
+            max_entry: config.http_max_entry.min(config.http_size),
+        }
+    }
+
+    pub fn max_entry(&self) -> usize {
+        self.max_entry
+    }
+
+    // The stored response for `url`, if it was chosen by the same request headers
+    pub fn lookup(&self, url: &str, req: &HeaderMap) -> Option<Arc<Entry>> {
+        let mut entries = self.entries.lock().unwrap();
+        let (_, entry) = entries.by_url.get(url)?;
+        if entry.vary.iter().any(|(n, v)| req.get(n) != v.as_ref()) {
+            return None;
+        }
+        let entry = entry.clone();
+        entries.touch(url);
+        Some(entry)
+    }
+
+    // Whether a shared cache may keep this response (RFC 9111 section 3)
+    pub fn storable(&self, req: &HeaderMap, status: StatusCode, resp: &HeaderMap) -> bool {
+        let d = directives(resp);
+        // Requests with credentials or cookies get answers meant for one
+        // reader, unless the site says they can be shared
+        let personal = req.contains_key(header::AUTHORIZATION) || req.contains_key(header::COOKIE);
+        let explicit = has(&d, "max-age")
+            || has(&d, "s-maxage")
+            || has(&d, "public")
+            || resp.contains_key(header::EXPIRES);
+        STORABLE.contains(&status)
+            && !has(&d, "no-store")
+            && !has(&d, "private")
+            && !has(&directives(req), "no-store")
+            && (!personal || has(&d, "public") || has(&d, "s-maxage"))
+            && !resp.contains_key(header::SET_COOKIE)
+            && !resp
+                .get_all(header::VARY)
+                .iter()
+                .any(|v| v.to_str().is_ok_and(|v| v.contains('*')))
+            && (explicit
+                || resp.contains_key(header::LAST_MODIFIED)
+                || resp.contains_key(header::ETAG))
+            && resp
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+                .is_none_or(|n| n <= self.max_entry)
+    }
+
+    pub fn insert(
+        &self,
+        url: &str,
+        req: &HeaderMap,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+    ) {
+        let entry = Entry::new(status, headers, body, req);
+        if entry.size() > self.max_entry {
+            return;
+        }
+        debug!("cached {} ({} bytes)", url, entry.size());
+        self.put(url, entry);
+    }
+
+    // Update a stored response from the 304 that revalidated it
+    pub fn refresh(&self, url: &str, entry: &Entry, not_modified: &HeaderMap) -> Arc<Entry> {
+        let mut headers = entry.headers.clone();
+        for name in not_modified.keys() {
+            if KEEP_ON_REFRESH.contains(name) {
+                continue;
+            }
+            headers.remove(name);
+            for v in not_modified.get_all(name) {
+                headers.append(name.clone(), v.clone());
+            }
+        }
+        let mut fresh = Entry::new(entry.status, headers, entry.body.clone(), &HeaderMap::new());
+        fresh.vary = entry.vary.clone();
+        self.put(url, fresh)
+    }
+
+    // The entries as JSON lines, least recently used first
+    pub fn export(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        let mut out = String::new();
+        for url in entries.by_use.values() {
+            let (_, entry) = &entries.by_url[url];
+            out.push_str(&serde_json::to_string(&Saved::new(url, entry)).unwrap_or_default());
+            out.push('\n');
+        }
+        out
+    }
+
+    // Add the entries from export(), skipping any that can't be read back.
+    // Returns how many were added.
+    pub fn import(&self, text: &str) -> usize {
+        let mut n = 0;
+        for line in text.lines() {
+            let Some((url, entry)) = serde_json::from_str::<Saved>(line)
+                .ok()
+                .and_then(Saved::entry)
+            else {
+                debug!("skipping bad saved HTTP cache entry");
+                continue;
+            };
+            if entry.size() <= self.max_entry {
+                self.put(&url, entry);
+                n += 1;
+            }
+        }
+        n
+    }
+
+    fn put(&self, url: &str, entry: Entry) -> Arc<Entry> {
+        let entry = Arc::new(entry);
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(url);
+        entries.size += entry.size();
+        entries.by_url.insert(url.to_string(), (0, entry.clone()));
+        entries.touch(url);
+        while entries.size > self.capacity {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.append(k.clone(), v.parse().unwrap());
+        }
+        h
+    }
+
+    fn cache(capacity: usize) -> HttpCache {
+        HttpCache::new(&config::Cache {
+            http_size: capacity,
+            http_max_entry: capacity,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_freshness() {
+        let req = HeaderMap::new();
+        let fresh = |h: &[(HeaderName, &str)]| {
+            Entry::new(StatusCode::OK, headers(h), Bytes::new(), &req).is_fresh()
+        };
+        assert!(fresh(&[(header::CACHE_CONTROL, "public, max-age=600")]));
+        assert!(!fresh(&[
+            (header::CACHE_CONTROL, "max-age=600"),
+            (header::AGE, "700")
+        ]));
+        assert!(!fresh(&[(header::CACHE_CONTROL, "no-cache, max-age=600")]));
+        assert!(!fresh(&[(header::EXPIRES, "0")]));
+        assert!(fresh(&[
+            (header::DATE, "Wed, 21 Oct 2026 07:28:00 GMT"),
+            (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]));
+        assert!(!fresh(&[(header::ETAG, "\"v1\"")]));
+    }
+
+    #[test]
+    fn test_storable() {
+        let c = cache(1000);
+        let req = HeaderMap::new();
+        let ok = |h: &[(HeaderName, &str)]| c.storable(&req, StatusCode::OK, &headers(h));
+        assert!(ok(&[(header::CACHE_CONTROL, "max-age=60")]));
+        assert!(ok(&[(header::ETAG, "\"a\"")]));
+        assert!(!ok(&[]));
+        assert!(!ok(&[(header::CACHE_CONTROL, "private, max-age=60")]));
+        assert!(!ok(&[(header::CACHE_CONTROL, "no-store")]));
+        assert!(!ok(&[(header::ETAG, "\"a\""), (header::SET_COOKIE, "a=b")]));
+        assert!(!ok(&[(header::ETAG, "\"a\""), (header::VARY, "*")]));
+        assert!(!ok(&[
+            (header::ETAG, "\"a\""),
+            (header::CONTENT_LENGTH, "5000")
+        ]));
+        let etag = headers(&[(header::ETAG, "\"a\"")]);
+        assert!(!c.storable(&req, StatusCode::PARTIAL_CONTENT, &etag));
+        let auth = headers(&[(header::AUTHORIZATION, "Basic eA==")]);
+        assert!(!c.storable(&auth, StatusCode::OK, &etag));
+        let cookie = headers(&[(header::COOKIE, "session=1")]);
+        assert!(!c.storable(&cookie, StatusCode::OK, &etag));
+        let public = headers(&[(header::CACHE_CONTROL, "public, max-age=60")]);
+        assert!(c.storable(&cookie, StatusCode::OK, &public));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let c = cache(1000);
+        let gzip = headers(&[(header::ACCEPT_ENCODING, "gzip")]);
+        let resp = headers(&[
+            (header::CACHE_CONTROL, "max-age=60"),
+            (header::ETAG, "\"a\""),
+        ]);
+        c.insert(
+            "https://a/x.css",
+            &gzip,
+            StatusCode::OK,
+            resp,
+            Bytes::from_static(b"body"),
+        );
+        let hit = c.lookup("https://a/x.css", &gzip).unwrap();
+        assert!(hit.is_fresh());
+        assert_eq!(hit.response().headers()[header::AGE], "0");
+        // Stored for another Accept-Encoding
+        assert!(c.lookup("https://a/x.css", &HeaderMap::new()).is_none());
+
+        let mut req = HeaderMap::new();
+        hit.add_validators(&mut req);
+        assert_eq!(req[header::IF_NONE_MATCH], "\"a\"");
+        let refreshed = c.refresh(
+            "https://a/x.css",
+            &hit,
+            &headers(&[(header::ETAG, "\"b\"")]),
+        );
+        assert_eq!(refreshed.response().headers()[header::ETAG], "\"b\"");
+        assert!(c.lookup("https://a/x.css", &gzip).is_some());
+
+        // Least recently used entries go first
+        c.insert(
+            "https://a/big",
+            &gzip,
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from(vec![0; 980]),
+        );
+        assert!(c.lookup("https://a/x.css", &gzip).is_none());
+        assert!(c.lookup("https://a/big", &gzip).is_some());
+        let entries = c.entries.lock().unwrap();
+        let size: usize = entries.by_url.values().map(|(_, e)| e.size()).sum();
+        assert_eq!((entries.size, entries.by_use.len()), (size, 1));
+    }
+
+    #[test]
+    fn test_export() {
+        let c = cache(1000);
+        let gzip = headers(&[(header::ACCEPT_ENCODING, "gzip")]);
+        let resp = headers(&[
+            (header::CACHE_CONTROL, "max-age=600"),
+            (header::CONTENT_TYPE, "text/css"),
+        ]);
+        c.insert(
+            "https://a/x.css",
+            &gzip,
+            StatusCode::OK,
+            resp,
+            Bytes::from_static(b"\x1f\x8b"),
+        );
+        c.insert(
+            "https://a/gone",
+            &HeaderMap::new(),
+            StatusCode::GONE,
+            headers(&[(header::ETAG, "\"g\"")]),
+            Bytes::new(),
+        );
+
+        let restored = cache(1000);
+        let saved = c.export();
+        assert_eq!(restored.import(&format!("{saved}not json\n")), 2);
+        let hit = restored.lookup("https://a/x.css", &gzip).unwrap();
+        assert!(hit.is_fresh());
+        assert_eq!(hit.body, Bytes::from_static(b"\x1f\x8b"));
+        assert_eq!(hit.headers[header::CONTENT_TYPE], "text/css");
+        assert!(
+            restored
+                .lookup("https://a/x.css", &HeaderMap::new())
+                .is_none()
+        );
+        let gone = restored
+            .lookup("https://a/gone", &HeaderMap::new())
+            .unwrap();
+        assert_eq!(gone.status, StatusCode::GONE);
+        // Least recently used first, as they were
+        assert_eq!(restored.export(), saved);
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let resp = headers(&[
+            (header::ETAG, "\"v1\""),
+            (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+        let req = |h: &[(HeaderName, &str)]| not_modified(&headers(h), &resp);
+        assert!(req(&[(header::IF_NONE_MATCH, "\"v0\", W/\"v1\"")]));
+        assert!(req(&[(header::IF_NONE_MATCH, "*")]));
+        assert!(!req(&[(header::IF_NONE_MATCH, "\"v0\"")]));
+        // If-None-Match wins over If-Modified-Since
+        assert!(!req(&[
+            (header::IF_NONE_MATCH, "\"v0\""),
+            (header::IF_MODIFIED_SINCE, "Thu, 22 Oct 2015 07:28:00 GMT"),
+        ]));
+        assert!(req(&[(
+            header::IF_MODIFIED_SINCE,
+            "Thu, 22 Oct 2015 07:28:00 GMT"
+        )]));
+        assert!(!req(&[(
+            header::IF_MODIFIED_SINCE,
+            "Tue, 20 Oct 2015 07:28:00 GMT"
+        )]));
+        assert!(!req(&[]));
+    }
+}
@@ -0,0 +1,170 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::HeaderMap;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+use crate::config::{self, Listen};
+use crate::connect::NoProxy;
+
+// Pause after a failed accept, which is usually out of file descriptors
+// and would fail again straight away
+pub const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+// A TCP or unix socket listener, so the accept loop doesn't care which
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, std::path::PathBuf),
+}
+
+impl Listener {
+    pub async fn bind(listen: &Listen) -> Result<Self> {
+        match listen {
+            Listen::Tcp(addr) => Ok(Listener::Tcp(
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("failed to listen on {addr}"))?,
+            )),
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                // A socket left behind by an earlier run would fail the bind
+                if std::fs::symlink_metadata(path).is_ok() {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("failed to remove {}", path.display()))?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("failed to listen on {}", path.display()))?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+            #[cfg(not(unix))]
+            Listen::Unix(_) => anyhow::bail!("unix sockets are not supported here"),
+        }
+    }
+
+    // Unix socket peers have no address; they count as local until
+    // Forwarded finds the client behind them
+    pub async fn accept(&self) -> io::Result<(Box<dyn Io>, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (stream, remote) = l.accept().await?;
+                Ok((Box::new(stream), remote))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l, _) => {
+                let (stream, _) = l.accept().await?;
+                Ok((Box::new(stream), SocketAddr::from(([127, 0, 0, 1], 0))))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Who a request is from when it comes through a reverse proxy. Only
+// proxies we trust get to say, or any client could pick its own address
+// and with it its own speech allowance.
+#[derive(Debug)]
+pub struct Forwarded {
+    // Listening on a unix socket, where every peer is a proxy
+    all: bool,
+    trusted: NoProxy,
+}
+
+impl Forwarded {
+    pub fn new(config: &config::Server) -> Self {
+        Forwarded {
+            all: matches!(config.listen, Listen::Unix(_)),
+            trusted: NoProxy::parse(&config.trusted_proxies.join(",")),
+        }
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.trusted.matches(&ip.to_canonical().to_string(), 0)
+    }
+
+    // The client's address: from a trusted peer the last X-Forwarded-For
+    // entry that isn't one of our proxies, or X-Real-IP
+    pub fn client(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        if !self.all && !self.trusts(peer.ip()) {
+            return peer;
+        }
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|a| a.trim().parse().ok())
+            .collect();
+        let client = forwarded
+            .iter()
+            .rev()
+            .find(|ip| !self.trusts(**ip))
+            .or(forwarded.first())
+            .copied()
+            .or_else(|| {
+                headers
+                    .get("x-real-ip")
+                    .and_then(|v| v.to_str().ok()?.trim().parse().ok())
+            });
+        match client {
+            Some(ip) => SocketAddr::new(ip, 0),
+            None => peer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.append(*k, v.parse().unwrap());
+        }
+        h
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let tcp = Forwarded::new(&config::Server {
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        });
+        let proxy: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let stranger: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        let xff = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.9, 10.0.0.3")]);
+        // The last hop before our proxies; what the client wrote comes earlier
+        assert_eq!(tcp.client(proxy, &xff).ip().to_string(), "203.0.113.9");
+        assert_eq!(tcp.client(stranger, &xff), stranger);
+        let real = headers(&[("x-real-ip", "203.0.113.10")]);
+        assert_eq!(tcp.client(proxy, &real).ip().to_string(), "203.0.113.10");
+        assert_eq!(tcp.client(proxy, &HeaderMap::new()), proxy);
+
+        let unix = Forwarded::new(&config::Server {
+            listen: "unix:/run/simplereading.sock".parse().unwrap(),
+            ..Default::default()
+        });
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let a = headers(&[("x-forwarded-for", "203.0.113.1")]);
+        let b = headers(&[("x-forwarded-for", "203.0.113.2")]);
+        assert_ne!(unix.client(local, &a), unix.client(local, &b));
+    }
+}
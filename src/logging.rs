@@ -0,0 +1,314 @@
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http_body_util::BodyExt;
+use hyper::body::{Bytes, Frame, SizeHint};
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Request, Response, Uri};
+use log::info;
+use serde_json::json;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use crate::body::Body;
+use crate::config::AccessLog;
+
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// What we learn about a request while handling it
+#[derive(Default)]
+struct Notes {
+    upstream: Option<String>,
+    cache: Option<&'static str>,
+}
+
+struct Scope {
+    id: String,
+    notes: Mutex<Notes>,
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<Scope>;
+}
+
+// The id of the request being handled, if any
+pub fn request_id() -> Option<String> {
+    CURRENT.try_with(|s| s.id.clone()).ok()
+}
+
+// Keep the current request's id for `f`, e.g. a task it spawns
+pub fn inherit<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    let scope = CURRENT.try_with(Arc::clone).ok();
+    async move {
+        match scope {
+            Some(scope) => CURRENT.scope(scope, f).await,
+            None => f.await,
+        }
+    }
+}
+
+// The upstream host the current request went to
+pub fn note_upstream(host: &str) {
+    let _ = CURRENT.try_with(|s| s.notes.lock().unwrap().upstream = Some(host.to_string()));
+}
+
+// How a cache answered the current request: hit, miss or revalidated
+pub fn note_cache(status: &'static str) {
+    let _ = CURRENT.try_with(|s| s.notes.lock().unwrap().cache = Some(status));
+}
+
+// Log lines carry the id of the request they were logged for; access log
+// lines are written as they are
+pub fn init(filter: &str) {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter))
+        .format(|buf, record| {
+            if record.target() == "access" {
+                return writeln!(buf, "{}", record.args());
+            }
+            let style = buf.default_level_style(record.level());
+            let id = request_id().map(|id| format!(" {id}")).unwrap_or_default();
+            writeln!(
+                buf,
+                "[{} {style}{:<5}{style:#} {}{id}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        })
+        .init();
+}
+
+// The request target as logged, without the ?token= that logs browsers in
+fn redact(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| {
+            if pair.starts_with("token=") {
+                "token=REDACTED"
+            } else {
+                pair
+            }
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+// One request's access log entry, written once its response body is sent
+pub struct Entry {
+    format: AccessLog,
+    scope: Arc<Scope>,
+    start: Instant,
+    time: OffsetDateTime,
+    remote: SocketAddr,
+    method: String,
+    uri: String,
+    version: String,
+    referer: String,
+    user_agent: String,
+    status: u16,
+}
+
+impl Entry {
+    pub fn new<B>(format: AccessLog, remote: SocketAddr, req: &Request<B>) -> Self {
+        let header = |name: HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-")
+                .to_string()
+        };
+        Entry {
+            format,
+            scope: Arc::new(Scope {
+                id: uuid::Uuid::new_v4().as_simple().to_string(),
+                notes: Mutex::default(),
+            }),
+            start: Instant::now(),
+            time: OffsetDateTime::now_utc(),
+            remote,
+            method: req.method().to_string(),
+            uri: redact(req.uri()),
+            version: format!("{:?}", req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            status: 0,
+        }
+    }
+
+    // Run the handler for this request, so its log lines carry the id
+    pub async fn scope<F: Future>(&self, f: F) -> F::Output {
+        CURRENT.scope(self.scope.clone(), f).await
+    }
+
+    // Tag the response with the request id and log it once it's sent
+    pub fn finish(mut self, mut resp: Response<Body>) -> Response<Body> {
+        if let Ok(id) = HeaderValue::from_str(&self.scope.id) {
+            resp.headers_mut().insert(REQUEST_ID.clone(), id);
+        }
+        if self.format == AccessLog::Off {
+            return resp;
+        }
+        self.status = resp.status().as_u16();
+        resp.map(|inner| {
+            Logged {
+                inner,
+                bytes: 0,
+                entry: Some(self),
+            }
+            .boxed()
+        })
+    }
+
+    fn line(&self, bytes: u64) -> String {
+        let notes = self.scope.notes.lock().unwrap();
+        let upstream = notes.upstream.as_deref().unwrap_or("-");
+        let cache = notes.cache.unwrap_or("-");
+        let elapsed = self.start.elapsed();
+        if self.format == AccessLog::Json {
+            return json!({
+                "time": self.time.format(&Rfc3339).unwrap_or_default(),
+                "id": self.scope.id,
+                "remote": self.remote.ip().to_string(),
+                "method": self.method,
+                "uri": self.uri,
+                "protocol": self.version,
+                "status": self.status,
+                "bytes": bytes,
+                "duration_ms": elapsed.as_secs_f64() * 1000.0,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "upstream": notes.upstream,
+                "cache": notes.cache,
+            })
+            .to_string();
+        }
+        let t = self.time;
+        let mut line = format!(
+            r#"{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] "{} {} {}" {} {}"#,
+            self.remote.ip(),
+            t.day(),
+            &t.month().to_string()[..3],
+            t.year(),
+            t.hour(),
+            t.minute(),
+            t.second(),
+            self.method,
+            quoted(&self.uri),
+            self.version,
+            self.status,
+            bytes
+        );
+        if self.format == AccessLog::Combined {
+            line.push_str(&format!(
+                r#" "{}" "{}""#,
+                quoted(&self.referer),
+                quoted(&self.user_agent)
+            ));
+        }
+        line.push_str(&format!(
+            " rt={:.3} upstream={upstream} cache={cache} id={}",
+            elapsed.as_secs_f64(),
+            self.scope.id
+        ));
+        line
+    }
+}
+
+fn quoted(s: &str) -> String {
+    s.replace('\\', r"\\").replace('"', "\\\"")
+}
+
+// A response body that writes the access log entry when it's dropped,
+// whether it was sent in full or the client went away
+struct Logged {
+    inner: Body,
+    bytes: u64,
+    entry: Option<Entry>,
+}
+
+impl hyper::body::Body for Logged {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, hyper::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.bytes += data.len() as u64;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for Logged {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            info!(target: "access", "{}", entry.line(self.bytes));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_entry() {
+        let req = Request::builder()
+            .uri("/?dest=https%3A%2F%2Fm.booklink.me%2Fbook-1-1.html")
+            .header(header::USER_AGENT, r#"Mozilla/5.0 "quoted""#)
+            .body(())
+            .unwrap();
+        let remote: SocketAddr = "192.0.2.7:51000".parse().unwrap();
+        let mut entry = Entry::new(AccessLog::Combined, remote, &req);
+        entry
+            .scope(async {
+                assert_eq!(request_id().as_deref(), Some(entry.scope.id.as_str()));
+                inherit(async { note_upstream("m.booklink.me") }).await;
+                note_cache("hit");
+            })
+            .await;
+        assert_eq!(request_id(), None);
+        entry.status = 200;
+
+        let line = entry.line(1234);
+        assert!(line.starts_with("192.0.2.7 - - ["));
+        assert!(line.contains(
+            r#""GET /?dest=https%3A%2F%2Fm.booklink.me%2Fbook-1-1.html HTTP/1.1" 200 1234 "-" "Mozilla/5.0 \"quoted\"" rt="#
+        ));
+        assert!(line.ends_with(&format!(
+            " upstream=m.booklink.me cache=hit id={}",
+            entry.scope.id
+        )));
+
+        entry.format = AccessLog::Json;
+        let value: serde_json::Value = serde_json::from_str(&entry.line(1234)).unwrap();
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["upstream"], "m.booklink.me");
+        assert_eq!(value["user_agent"], r#"Mozilla/5.0 "quoted""#);
+
+        let uri: Uri = "/?token=0123456789abcdef&dest=x".parse().unwrap();
+        assert_eq!(redact(&uri), "/?token=REDACTED&dest=x");
+    }
+}
@@ -1,42 +1,99 @@
 use anyhow::Result;
+use clap::Parser;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::{Request, Response};
-use hyper_util::rt::TokioIo;
-use log::{debug, info};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful;
+use log::{debug, info, warn};
 use readability::extractor::{get_dom, Product};
 use readability::markup5ever_rcdom::Node;
 use readability::markup5ever_rcdom::NodeData::Element;
 use regex::Regex;
 use std::collections::VecDeque;
+use std::convert::Infallible;
 use std::env;
 use std::io::BufReader;
 
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, net::SocketAddr};
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use tokio::net::TcpListener;
 use tokio::task;
 use url::Url;
+
+use crate::error::AppError;
+
+mod adblock;
+mod assets;
+mod auth;
+mod body;
+mod client;
+mod config;
+mod connect;
+mod cookies;
+mod error;
+mod fetch;
+mod health;
+mod httpcache;
+mod listener;
+mod logging;
+mod metrics;
+mod policy;
+mod prefetch;
 mod proxy;
+mod ratelimit;
+mod rewrite;
+mod search;
+mod sites;
+mod state;
+mod tls;
+mod ttslimit;
 mod utils;
 
 #[derive(Debug)]
 pub struct AppContext {
-    booksite: String,
+    sites: sites::Sites,
     fontsize: String,
     ua: String,
     host: String,
     port: String,
     scheme: String,
+    tts: config::Tts,
+    tts_limit: ttslimit::TtsLimiter,
+    rewrite: Arc<rewrite::Rewriter>,
+    adblock: Option<adblock::AdBlock>,
+    proxy: config::Proxy,
+    http_cache: Option<Arc<httpcache::HttpCache>>,
+    search: config::Search,
+    cookies: Option<cookies::Jar>,
+    health: health::Health,
+    auth: auth::Auth,
+    policy: policy::UrlPolicy,
+    access_log: config::AccessLog,
+    // Finds the client behind a reverse proxy
+    forwarded: listener::Forwarded,
+    prefetch: prefetch::Prefetcher,
+    client: client::Upstream,
+}
+
+// A chapter with all its continuation pages merged
+#[derive(Debug)]
+pub struct Chapter {
+    title: String,
+    text: String,
+    // Absolute url of the next chapter, if the page links to one
+    next: Option<String>,
 }
 
 async fn handle(
     context: Arc<AppContext>,
+    remote: SocketAddr,
     req: Request<hyper::body::Incoming>,
-) -> Result<Response<Full<Bytes>>> {
+) -> Result<Response<body::Body>> {
     let params: HashMap<String, String> = req
         .uri()
         .query()
@@ -46,6 +103,27 @@ async fn handle(
                 .collect()
         })
         .unwrap_or_default();
+    if req.uri().path() == auth::LOGIN_PATH || req.uri().path() == auth::LOGOUT_PATH {
+        return context.auth.login(req).await;
+    }
+    if let Some(resp) = context.auth.check(&req).await {
+        return Ok(resp);
+    }
+    if let Some(path) = req.uri().path().strip_prefix(assets::PREFIX) {
+        return assets::respond(req.headers(), path);
+    }
+    match req.uri().path() {
+        "/healthz" => return context.health.live(),
+        "/readyz" => return context.health.ready(context.client.connector()).await,
+        _ => {}
+    }
+    if req.uri().path() == "/metrics" {
+        return metrics::respond(req.headers());
+    }
+    if req.uri().path() == "/search" {
+        let query = params.get("q").map_or("", |q| q.trim());
+        return search::respond(&context, req.headers(), query).await;
+    }
     if let Some(dest) = params.get("dest").cloned().filter(|d| !d.is_empty()) {
         info!("dest: {}", &dest);
         if dest.contains("fkzww.net") {
@@ -53,110 +131,140 @@ async fn handle(
             let r = Response::builder()
                 .status(hyper::http::StatusCode::FOUND)
                 .header(hyper::header::LOCATION, dest)
-                .body(Full::new(Bytes::new()))?;
+                .body(body::full(Bytes::new()))?;
             return Ok(r);
         } else {
-            let p0 = get_all_txt(dest).await?;
+            let p0 = context.prefetch.chapter(&context, &dest).await?;
+            let listener = listener(&context, remote, &req);
+            context
+                .prefetch
+                .schedule(context.clone(), remote.ip(), listener, &p0);
             let html = format!(
-                r#"<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1.0" /><title>{title}</title></head><body><h3>{title}</h3><style> p{{text-indent:2em; font-size:{fontsize}px;}}</style><div id="div1" style="text-align:center"><audio id="au"><source src = "data:audio/mpeg;base64,SUQzBAAAAAABEVRYWFgAAAAtAAADY29tbWVudABCaWdTb3VuZEJhbmsuY29tIC8gTGFTb25vdGhlcXVlLm9yZwBURU5DAAAAHQAAA1N3aXRjaCBQbHVzIMKpIE5DSCBTb2Z0d2FyZQBUSVQyAAAABgAAAzIyMzUAVFNTRQAAAA8AAANMYXZmNTcuODMuMTAwAAAAAAAAAAAAAAD/80DEAAAAA0gAAAAATEFNRTMuMTAwVVVVVVVVVVVVVUxBTUUzLjEwMFVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVf/zQsRbAAADSAAAAABVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVf/zQMSkAAADSAAAAABVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVVV"></audio><button id="listen" type="button" onclick="listen()">Listen</button></div>{content}
-<script type="text/javascript">
-function chg(e) {{
-    if (e === "dark") {{
-        document.body.style.color = "white";
-        document.body.style.backgroundColor = "black";
-    }} else {{
-        document.body.style.color = "black";
-        document.body.style.backgroundColor = "white";
-    }}
-}}
-if (window.matchMedia && window.matchMedia('(prefers-color-scheme: dark)').matches) {{
-    chg("dark");
-}} else {{
-    chg("bright");
-}}
-window.matchMedia('(prefers-color-scheme: dark)').addEventListener('change', event => {{
-    const newColorScheme = event.matches ? "dark" : "light";
-    chg(newColorScheme);
-}});
-function listen() {{
-    let full = window.location.href;
-    let dest = full.replace("dest=", "listen=");
-    let btn = document.getElementById("listen");
-    let div = document.getElementById("div1");
-    let au = document.getElementById("au");
-    au.autoplay = true;
-    try {{
-        au.src=dest;
-        au.addEventListener("canplaythrough", (event) => {{
-            au.play();
-        }});
-        au.controls = true;
-        div.insertBefore(au, btn);
-        btn.style.display = "none";
-    }} catch (e) {{
-        alert(e.stack);
-    }}
-}}
-</script>
+                r#"<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1.0" /><title>{title}</title>{stylesheet}<style> p{{font-size:{fontsize}px;}}</style></head><body><h3>{title}</h3><div id="div1"><audio id="au"><source src="{silent}"></audio><button id="listen" type="button" onclick="listen()">Listen</button></div>{content}
+{theme}{listen}
 </body></html>"#,
+                stylesheet = assets::stylesheet(),
+                silent = assets::url("silent.mp3"),
+                theme = assets::script("theme.js"),
+                listen = assets::script("listen.js"),
                 title = p0.title,
                 fontsize = context.fontsize,
                 content = p0.text
             );
             debug!("html: {}", &html);
-            let mut encoding = req
-                .headers()
-                .get(hyper::header::ACCEPT_ENCODING)
-                .and_then(|v| v.to_str().ok())
-                .unwrap_or("")
-                .to_string();
-            let new_body = utils::compress_body(&html.into_bytes(), &mut encoding)?;
-            let new_resp = Response::builder()
-                .status(hyper::http::StatusCode::OK)
-                .header(hyper::header::CONTENT_TYPE, "text/html")
-                .header(hyper::header::CONTENT_ENCODING, &encoding)
-                .header(hyper::header::CONTENT_LENGTH, new_body.len().to_string())
-                .body(Full::from(new_body))?;
+            let mut headers = hyper::HeaderMap::new();
+            headers.insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+            );
+            let new_body = utils::encode_body(req.headers(), &mut headers, html.into_bytes())?;
+            let mut new_resp = Response::new(body::full(new_body));
+            *new_resp.headers_mut() = headers;
             return Ok(new_resp);
         }
     } else if let Some(listen) = params.get("listen").cloned() {
-        let mut all = get_all_txt(listen).await?.text;
-        all = all.replace("</p>", "");
-        let lines = all.split("<p>").collect::<Vec<&str>>();
-        let n = 10;
-        let start = r#"<speak version="1.0" xmlns="http://www.w3.org/2001/10/synthesis" xmlns:mstts="https://www.w3.org/2001/mstts" xml:lang="zh-CN"> <voice name="zh-CN-XiaoxiaoNeural"> <prosody rate="+50.00%">"#;
-        let end = r#"</prosody> </voice> </speak>"#;
-        let mut mp3 = Vec::new();
-        let size = lines.len() / n;
-        debug!("size={size}");
-        let mut handles = Vec::new();
-        for i in 0..n {
-            let mut ssml = String::from(start);
-            let s = if i == n - 1 {
-                lines[i * size..].join("")
-            } else {
-                lines[i * size..(i + 1) * size].join("")
-            };
-            ssml.push_str(&s);
-            ssml.push_str(end);
-            debug!("ssml: {}", &ssml);
-            let handle = task::spawn(async move { utils::get_mp3(&ssml).await });
-            handles.push(handle);
-        }
-        for handle in handles {
-            if let Ok(result) = handle.await {
-                mp3.extend_from_slice(&result?);
-            }
-        }
-        let mut resp = Response::new(Full::from(mp3));
-        resp.headers_mut()
-            .append(hyper::header::CONTENT_TYPE, "audio/mpeg".parse()?);
+        let listener = listener(&context, remote, &req);
+        let _listening = context.tts_limit.listen(&listener)?;
+        let mp3 = context.prefetch.mp3(&context, &listener, &listen).await?;
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(hyper::header::CONTENT_TYPE, "audio/mpeg".parse()?);
+        let mp3 = utils::encode_body(req.headers(), &mut headers, mp3.to_vec())?;
+        let mut resp = Response::new(body::full(mp3));
+        *resp.headers_mut() = headers;
         return Ok(resp);
     }
-    proxy::call(context.clone(), &context.booksite, req).await
+    let site = context.sites.select(request_host(&req), req.uri().path());
+    proxy::call(context.clone(), site, req).await
+}
+
+// Whose allowance speech synthesis counts against: the user if they
+// logged in as one, otherwise their address
+fn listener<B>(context: &AppContext, remote: SocketAddr, req: &Request<B>) -> String {
+    match context.auth.user(req) {
+        Some(user) => format!("user:{user}"),
+        None => remote.ip().to_string(),
+    }
+}
+
+// The host the client asked for, from the URI on HTTP/2 or the Host header
+fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    req.uri().host().or_else(|| {
+        req.headers()
+            .get(hyper::header::HOST)
+            .and_then(|h| h.to_str().ok())
+    })
+}
+
+// Serve a request under a new request id, logging it once it's sent
+async fn serve(
+    context: Arc<AppContext>,
+    remote: SocketAddr,
+    req: Request<hyper::body::Incoming>,
+) -> Result<Response<body::Body>, Infallible> {
+    let remote = context.forwarded.client(remote, req.headers());
+    let entry = logging::Entry::new(context.access_log, remote, &req);
+    let resp = entry.scope(respond(context, remote, req)).await;
+    Ok(entry.finish(resp))
+}
+
+// Handle a request, turning failures into an error page instead of a dropped connection
+async fn respond(
+    context: Arc<AppContext>,
+    remote: SocketAddr,
+    req: Request<hyper::body::Incoming>,
+) -> Response<body::Body> {
+    let retry = req.uri().to_string();
+    let params: HashMap<String, String> = req
+        .uri()
+        .query()
+        .map(|v| {
+            url::form_urlencoded::parse(v.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+    let origin = params
+        .get("dest")
+        .or_else(|| params.get("listen"))
+        .cloned()
+        .unwrap_or_else(|| {
+            context
+                .sites
+                .select(request_host(&req), req.uri().path())
+                .forward(req.uri().path_and_query().map_or("/", |p| p.as_str()))
+        });
+    let route = route(req.uri().path(), &params);
+    let start = Instant::now();
+    let resp = match handle(context.clone(), remote, req).await {
+        Ok(resp) => resp,
+        Err(e) => error::error_page(&e, &context.fontsize, &retry, Some(&origin)),
+    };
+    metrics::request(route, resp.status().as_u16(), start.elapsed());
+    resp
+}
+
+// What kind of request this is, as `handle` tells them apart
+fn route(path: &str, params: &HashMap<String, String>) -> &'static str {
+    if path == auth::LOGIN_PATH || path == auth::LOGOUT_PATH {
+        "auth"
+    } else if path.starts_with(assets::PREFIX) {
+        "asset"
+    } else if path == "/search" {
+        "search"
+    } else if path == "/metrics" {
+        "metrics"
+    } else if path == "/healthz" || path == "/readyz" {
+        "health"
+    } else if params.get("dest").is_some_and(|d| !d.is_empty()) {
+        "reader"
+    } else if params.contains_key("listen") {
+        "listen"
+    } else {
+        "proxy"
+    }
 }
 
-async fn get_all_txt(dest: String) -> Result<Product> {
+async fn get_all_txt(context: &AppContext, dest: String) -> Result<Chapter> {
     let base = Url::parse(&dest)?;
     let re = if let (Some(first_idx), Some(last_idx)) = (dest.rfind('/'), dest.rfind('.')) {
         let first = first_idx + 1;
@@ -169,9 +277,11 @@ async fn get_all_txt(dest: String) -> Result<Product> {
     } else {
         Regex::new(r"^$")? // Never matches
     };
-    let body = fetch_novel(&dest).await?;
-    let mut p0 = get_content(&body[..], &Url::parse(&dest)?, &re)?;
+    let body = fetch_novel(context, &dest).await?;
+    let (mut p0, mut chapter) = get_content(&body[..], &base, &re)?;
+    let mut page_url = base.clone();
     let mut next = p0.content.clone();
+    let mut pages = 1;
     while !next.is_empty() {
         debug!("next: {}", &next);
         let next_url = if next.contains("http") {
@@ -179,60 +289,309 @@ async fn get_all_txt(dest: String) -> Result<Product> {
         } else {
             base.join(&next)?
         };
-        let resp_orig = fetch_novel(next_url.as_str()).await?;
-        let p1 = get_content(&resp_orig[..], &next_url, &re)?;
+        let resp_orig = fetch_novel(context, next_url.as_str()).await?;
+        let (p1, c1) = get_content(&resp_orig[..], &next_url, &re)?;
         p0.text += &p1.text;
         next = p1.content;
+        chapter = c1;
+        page_url = next_url;
+        pages += 1;
     }
-    Ok(p0)
+    metrics::chapter_pages(pages);
+    // The last page is the one that links to the next chapter
+    let next = page_url
+        .join(&chapter)
+        .ok()
+        .filter(|u| !chapter.is_empty() && u.host() == base.host() && *u != base)
+        .map(String::from);
+    debug!("next chapter: {:?}", &next);
+    Ok(Chapter {
+        title: p0.title,
+        text: p0.text,
+        next,
+    })
+}
+
+// Synthesize the text of a chapter, using ten concurrent requests
+async fn synthesize(context: &AppContext, text: String) -> Result<Vec<u8>> {
+    let all = text.replace("</p>", "");
+    let lines = all.split("<p>").collect::<Vec<&str>>();
+    let tts = &context.tts;
+    let n = tts.chunks;
+    let start = format!(
+        r#"<speak version="1.0" xmlns="http://www.w3.org/2001/10/synthesis" xmlns:mstts="https://www.w3.org/2001/mstts" xml:lang="{}"> <voice name="{}"> <prosody rate="{}">"#,
+        utils::escape_html(&tts.lang),
+        utils::escape_html(&tts.voice),
+        utils::escape_html(&tts.rate)
+    );
+    let end = r#"</prosody> </voice> </speak>"#;
+    let mut mp3 = Vec::new();
+    let size = lines.len() / n;
+    debug!("size={size}");
+    let connections = Arc::new(context.tts_limit.connections().await?);
+    let mut handles = Vec::new();
+    for i in 0..n {
+        let mut ssml = start.clone();
+        let s = if i == n - 1 {
+            lines[i * size..].join("")
+        } else {
+            lines[i * size..(i + 1) * size].join("")
+        };
+        ssml.push_str(&s);
+        ssml.push_str(end);
+        debug!("ssml: {}", &ssml);
+        let connector = context.client.connector().clone();
+        let connections = connections.clone();
+        let timeout = Duration::from_secs(tts.timeout);
+        let handle = task::spawn(logging::inherit(async move {
+            let _connections = connections;
+            let start = Instant::now();
+            // A stalled connection would hold the permits for good
+            let chunk = tokio::time::timeout(timeout, utils::get_mp3(&connector, &ssml))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("no audio after {timeout:?}")));
+            metrics::tts_chunk(start.elapsed(), chunk.is_ok());
+            chunk
+        }));
+        handles.push(handle);
+    }
+    for handle in handles {
+        if let Ok(result) = handle.await {
+            let chunk = result.map_err(|e| AppError::Tts {
+                reason: format!("{e:#}"),
+            })?;
+            mp3.extend_from_slice(&chunk);
+        }
+    }
+    Ok(mp3)
+}
+
+// Everything the handlers share; `tls` is whether we serve https ourselves
+fn build_context(config: &config::Config, tls: bool) -> Result<AppContext> {
+    metrics::set_hosts(config.upstreams().iter().flat_map(|u| {
+        let search = u.search.as_ref().map(|s| s.url.as_str());
+        [Some(u.url.as_str()), search]
+            .into_iter()
+            .flatten()
+            .filter_map(|url| Some(url::Url::parse(url).ok()?.host_str()?.to_string()))
+    }));
+    let policy = policy::UrlPolicy::new(&config.fetch, &config.upstreams());
+    Ok(AppContext {
+        sites: sites::Sites::new(config.upstreams())?,
+        fontsize: config.reader.fontsize.to_string(),
+        ua: config.site.user_agent.clone(),
+        host: config.server.host.clone(),
+        port: match &config.server.listen {
+            // Links point straight at us while developing
+            config::Listen::Tcp(addr) if config.server.dev && config.server.port.is_empty() => {
+                addr.port().to_string()
+            }
+            _ => config.server.port.clone(),
+        },
+        scheme: config
+            .server
+            .scheme
+            .clone()
+            .unwrap_or_else(|| if tls { "https" } else { "http" }.to_string()),
+        tts: config.tts.clone(),
+        tts_limit: ttslimit::TtsLimiter::new(&config.tts),
+        rewrite: Arc::new(rewrite::Rewriter::new(&config.rewrite)?),
+        adblock: if config.adblock.enabled {
+            Some(adblock::AdBlock::new(&config.adblock)?)
+        } else {
+            None
+        },
+        proxy: config.proxy.clone(),
+        http_cache: (config.cache.http_size > 0)
+            .then(|| Arc::new(httpcache::HttpCache::new(&config.cache))),
+        search: config.search.clone(),
+        cookies: if config.cookies.jar || config.cookies.file.is_some() {
+            Some(cookies::Jar::new(&config.cookies)?)
+        } else {
+            None
+        },
+        health: health::Health::new(config),
+        auth: auth::Auth::new(
+            &config.auth,
+            config
+                .server
+                .scheme
+                .as_deref()
+                .map_or(tls, |s| s == "https"),
+        )?,
+        access_log: config.server.access_log,
+        forwarded: listener::Forwarded::new(&config.server),
+        prefetch: prefetch::Prefetcher::new(&config.cache),
+        client: client::Upstream::new(
+            client::ClientConfig::new(&config.client),
+            ratelimit::Limiter::new(&config.rate_limit)?,
+            policy.addresses(),
+        )?,
+        policy,
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let dev = env::var("DEV").is_ok();
+    let args = config::Args::parse();
+    let config = match config::Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("simplereading: {e:#}");
+            std::process::exit(2);
+        }
+    };
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    if let Some(config::Command::Fetch { url, format }) = &args.command {
+        // Only warnings and errors, on stderr, so stdout is just the chapter
+        logging::init(&env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string()));
+        let context = build_context(&config, false)?;
+        if let Some(dir) = &config.cache.dir {
+            state::load(&context, dir);
+        }
+        std::process::exit(fetch::run(&context, url, *format).await);
+    }
+    let dev = config.server.dev;
     let log_level = if dev {
         "debug".to_string()
     } else {
         env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string())
     };
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&log_level)).init();
-    let localport = env::var("LOCAL_PORT").unwrap_or_else(|_| "9005".to_string());
-    let listenlocal = env::var("LISTEN_LOCAL").is_ok();
-    let listenaddr = match listenlocal {
-        true => [127, 0, 0, 1],
-        false => [0, 0, 0, 0],
-    };
-    let context = AppContext {
-        booksite: "https://m.booklink.me".to_string(),
-        fontsize: env::var("FONTSIZE").unwrap_or_else(|_| "17".to_string()),
-        ua: "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/143.0.0.0 Safari/537.36".to_string(),
-        host: env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
-        port: if dev {
-            localport.clone()
-        }else{
-            env::var("PORT").unwrap_or_else(|_| "".to_string())
-        },
-        scheme: env::var("SCHEME").unwrap_or_else(|_| "http".to_string()),
-    };
+    logging::init(&log_level);
+    let tls = tls::TlsConfig::new(&config.tls);
+    let context = build_context(&config, tls.is_some())?;
+    if let Some(dir) = &config.cache.dir {
+        state::load(&context, dir);
+    }
     info!("context: {:?}", &context);
     let c = Arc::new(context);
+    c.rewrite.clone().watch();
 
-    // Run the server like above...
-    let addr = SocketAddr::from((listenaddr, localport.parse().unwrap()));
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on: {}", addr);
+    let listener = listener::Listener::bind(&config.server.listen).await?;
+    info!("Listening on: {}", config.server.listen);
+
+    let mut redirect = None;
+    let acceptor = match tls {
+        Some(tls) => {
+            if let Some(port) = tls.redirect_port {
+                let ip = match config.server.listen {
+                    config::Listen::Tcp(addr) => addr.ip(),
+                    config::Listen::Unix(_) => [0, 0, 0, 0].into(),
+                };
+                let addr = SocketAddr::new(ip, port);
+                let listener = TcpListener::bind(addr).await?;
+                info!("Redirecting HTTP to HTTPS on: {}", addr);
+                redirect = Some(tokio::spawn(tls::redirect(
+                    listener,
+                    c.host.clone(),
+                    c.port.clone(),
+                )));
+            }
+            let store = tls::CertStore::load(tls)?;
+            store.clone().watch();
+            Some(store.acceptor())
+        }
+        None => None,
+    };
 
+    let grace = Duration::from_secs(config.server.shutdown_grace);
+    let graceful = graceful::GracefulShutdown::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(listener::ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            name = &mut signal => {
+                info!("Received {}, no longer accepting connections", name);
+                break;
+            }
+        };
         let c = c.clone();
-        let service = service_fn(move |req| handle(c.clone(), req));
-
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                println!("Failed to serve connection: {:?}", err);
+        let watcher = graceful.watcher();
+        match acceptor.clone() {
+            Some(acceptor) => {
+                tokio::task::spawn(async move {
+                    match tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                        .await
+                    {
+                        Ok(Ok(stream)) => {
+                            serve_connection(c, remote, TokioIo::new(stream), watcher).await
+                        }
+                        Ok(Err(err)) => debug!("TLS handshake with {} failed: {:?}", remote, err),
+                        Err(_) => debug!("TLS handshake with {} timed out", remote),
+                    }
+                });
             }
-        });
+            None => {
+                tokio::task::spawn(serve_connection(c, remote, TokioIo::new(stream), watcher));
+            }
+        }
+    }
+
+    // Let in-flight requests finish, then drop whatever is left
+    drop(listener);
+    if let Some(redirect) = redirect {
+        redirect.abort();
+    }
+    c.prefetch.cancel();
+    info!(
+        "Draining {} connection(s), waiting up to {:?}",
+        graceful.count(),
+        grace
+    );
+    tokio::select! {
+        _ = graceful.shutdown() => info!("All connections closed"),
+        _ = tokio::time::sleep(grace) => warn!("Grace period expired, closing remaining connections"),
+    }
+    if let Some(dir) = &config.cache.dir {
+        state::save(&c, dir);
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+// Resolves with the name of the first SIGINT or SIGTERM received
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut term = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = term.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+async fn serve_connection<I>(
+    context: Arc<AppContext>,
+    remote: SocketAddr,
+    io: I,
+    watcher: graceful::Watcher,
+) where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| serve(context.clone(), remote, req));
+    // HTTP/1.1, or HTTP/2 via ALPN or prior knowledge (h2c)
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection(io, service);
+    if let Err(err) = watcher.watch(conn).await {
+        info!("Failed to serve connection: {:?}", err);
     }
 }
 
@@ -273,43 +632,117 @@ fn get_next_link(node: Rc<Node>, re: &Regex) -> String {
     "".to_string()
 }
 
-fn get_content(content: &[u8], url: &Url, re: &Regex) -> Result<Product> {
+// Find the link to the next chapter: an anchor whose text says so, or rel="next"
+fn get_next_chapter_link(node: Rc<Node>, re: &Regex) -> String {
+    const LABELS: [&str; 4] = ["下一章", "下章", "下一节", "下一页"];
+    let mut queue = VecDeque::new();
+    queue.push_back(node);
+    let mut found: Vec<(usize, String)> = Vec::new();
+
+    while let Some(handle) = queue.pop_front() {
+        for child in handle.children.borrow().iter() {
+            let c = child.clone();
+            if let Element {
+                ref name,
+                ref attrs,
+                ..
+            } = c.data
+                && name.local.as_ref() == "a"
+            {
+                let attrs = attrs.borrow();
+                let attr = |n: &str| {
+                    attrs
+                        .iter()
+                        .find(|a| a.name.local.as_ref() == n)
+                        .map(|a| a.value.to_string())
+                };
+                if let Some(href) = attr("href").filter(|h| {
+                    !h.is_empty() && !h.starts_with('#') && !h.starts_with("javascript")
+                }) {
+                    let text = node_text(&c);
+                    let rank = LABELS
+                        .iter()
+                        .position(|l| text.trim() == *l || text.contains(l))
+                        .or_else(|| {
+                            (attr("rel").as_deref() == Some("next")).then_some(LABELS.len())
+                        });
+                    // "下一页" may just be the next part of the same chapter
+                    let continuation = href
+                        .rfind('/')
+                        .zip(href.rfind('.'))
+                        .is_some_and(|(f, l)| f < l && re.is_match(&href[f + 1..l]));
+                    if let Some(rank) = rank.filter(|_| !continuation) {
+                        found.push((rank, href));
+                    }
+                }
+            }
+            queue.push_back(c);
+        }
+    }
+    found
+        .into_iter()
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, href)| href)
+        .unwrap_or_default()
+}
+
+fn node_text(node: &Rc<Node>) -> String {
+    let mut text = String::new();
+    if let readability::markup5ever_rcdom::NodeData::Text { ref contents } = node.data {
+        text.push_str(&contents.borrow());
+    }
+    for child in node.children.borrow().iter() {
+        text.push_str(&node_text(child));
+    }
+    text
+}
+
+// Extract a page. The product's content is the link to the next part of the
+// chapter; the returned string is the link to the next chapter.
+fn get_content(content: &[u8], url: &Url, re: &Regex) -> Result<(Product, String)> {
+    let extract_error = |e: &dyn std::fmt::Display| AppError::Extract {
+        url: url.to_string(),
+        reason: e.to_string(),
+    };
     let mut bf = BufReader::new(content);
-    let dom = get_dom(&mut bf)?;
+    let dom = get_dom(&mut bf).map_err(|e| extract_error(&e))?;
 
     let next = get_next_link(dom.document.clone(), re);
     debug!("next: {}", &next);
-    let mut p = readability::extractor::extract(dom, url)?;
+    let chapter = get_next_chapter_link(dom.document.clone(), re);
+    let mut p = readability::extractor::extract(dom, url).map_err(|e| extract_error(&e))?;
     p.content = next;
-    Ok(p)
+    Ok((p, chapter))
 }
 
-async fn fetch_novel(url: &str) -> Result<Vec<u8>> {
-    use http_body_util::BodyExt;
-    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+async fn fetch_novel(context: &AppContext, url: &str) -> Result<Vec<u8>> {
     use std::io::Read;
 
-    let https = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_webpki_roots()
-        .https_or_http()
-        .enable_http1()
-        .enable_http2()
-        .build();
-    let client = Client::builder(TokioExecutor::new()).build(https);
-
     let mut current_url = url.to_string();
     let max_redirects = 10;
 
     for _ in 0..max_redirects {
-        let req = hyper::Request::builder()
+        context.policy.check(&current_url)?;
+        let mut req = hyper::Request::builder()
             .method("GET")
             .uri(&current_url)
-            .header(hyper::header::USER_AGENT, "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/143.0.0.0 Safari/537.36")
-            .header(hyper::header::ACCEPT_ENCODING, "gzip, deflate, br")
-            .body(http_body_util::Empty::<Bytes>::new())?;
+            .header(hyper::header::USER_AGENT, &context.ua)
+            .header(hyper::header::ACCEPT_ENCODING, "gzip, deflate, br");
+        let page = Url::parse(&current_url)?;
+        if let Some(cookie) = context.cookies.as_ref().and_then(|jar| jar.header(&page)) {
+            req = req.header(hyper::header::COOKIE, cookie);
+        }
+        let req = req.body(Full::new(Bytes::new()))?;
 
-        let resp: hyper::Response<hyper::body::Incoming> = client.request(req).await?;
+        let resp = context.client.request(req).await?;
         let status = resp.status();
+        if let Some(jar) = &context.cookies {
+            for v in resp.headers().get_all(hyper::header::SET_COOKIE) {
+                if let Ok(v) = v.to_str() {
+                    jar.store(&page, v);
+                }
+            }
+        }
 
         if status.is_redirection() {
             if let Some(location) = resp.headers().get(hyper::header::LOCATION) {
@@ -317,8 +750,7 @@ async fn fetch_novel(url: &str) -> Result<Vec<u8>> {
                 current_url = if location_str.starts_with("http") {
                     location_str.to_string()
                 } else {
-                    let base = Url::parse(&current_url)?;
-                    base.join(location_str)?.to_string()
+                    page.join(location_str)?.to_string()
                 };
                 debug!("Redirecting to: {}", current_url);
                 continue;
@@ -332,38 +764,65 @@ async fn fetch_novel(url: &str) -> Result<Vec<u8>> {
             .unwrap_or("")
             .to_string();
 
-        let body_bytes = resp.collect().await?.to_bytes().to_vec();
-
-        let html = match encoding.as_str() {
-            "gzip" => {
-                let mut decoder = flate2::read::GzDecoder::new(&body_bytes[..]);
-                let mut buf = Vec::new();
-                decoder.read_to_end(&mut buf)?;
-                buf
-            }
-            "deflate" => {
-                let mut decoder = flate2::read::DeflateDecoder::new(&body_bytes[..]);
-                let mut buf = Vec::new();
-                decoder.read_to_end(&mut buf)?;
-                buf
+        if !status.is_success() {
+            return Err(AppError::UpstreamStatus {
+                url: current_url,
+                status,
             }
-            "br" => {
-                let mut decoder = brotli::Decompressor::new(&body_bytes[..], body_bytes.len());
+            .into());
+        }
+
+        let body_bytes = context
+            .client
+            .collect(&current_url, resp.into_body(), context.policy.max_size)
+            .await?;
+
+        let decode_error = |e: &dyn std::fmt::Display| AppError::Decode {
+            url: current_url.clone(),
+            reason: e.to_string(),
+        };
+        let decoder: Option<Box<dyn Read + '_>> = match encoding.as_str() {
+            "gzip" => Some(Box::new(flate2::read::GzDecoder::new(&body_bytes[..]))),
+            "deflate" => Some(Box::new(flate2::read::DeflateDecoder::new(&body_bytes[..]))),
+            "br" => Some(Box::new(brotli::Decompressor::new(
+                &body_bytes[..],
+                body_bytes.len(),
+            ))),
+            _ => None,
+        };
+        let html = match decoder {
+            Some(decoder) => {
+                // fetch.max_size holds for the page, not just what was sent
+                let max = context.policy.max_size;
                 let mut buf = Vec::new();
-                decoder.read_to_end(&mut buf)?;
+                decoder
+                    .take(max as u64 + 1)
+                    .read_to_end(&mut buf)
+                    .map_err(|e| decode_error(&e))?;
+                if buf.len() > max {
+                    return Err(AppError::Forbidden {
+                        url: current_url,
+                        reason: format!("page is larger than {max} bytes once decompressed"),
+                    }
+                    .into());
+                }
                 buf
             }
-            _ => body_bytes,
+            None => body_bytes.to_vec(),
         };
 
         let r = if let Ok(r) = String::from_utf8(html.clone()) {
             r
         } else {
-            utils::to_utf8(&html, "gb18030")?
+            utils::to_utf8(&html, "gb18030").map_err(|e| decode_error(&e))?
         };
 
         return Ok(r.as_bytes().to_vec());
     }
 
-    Err(anyhow::anyhow!("Too many redirects"))
+    Err(AppError::UpstreamUnreachable {
+        url: url.to_string(),
+        reason: "too many redirects".to_string(),
+    }
+    .into())
 }
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::Response;
+use hyper::header::{self, HeaderMap, HeaderValue};
+
+use crate::body::{self, Body};
+use crate::utils;
+
+// Latency buckets, in seconds
+const SECONDS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+// Pages fetched for one chapter
+const PAGES: &[f64] = &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 15.0, 20.0];
+
+// Name, type and help of each metric, in the order they're listed
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "simplereading_requests_total",
+        "counter",
+        "Requests served, by route and status.",
+    ),
+    (
+        "simplereading_request_duration_seconds",
+        "histogram",
+        "Time to produce response headers, by route.",
+    ),
+    (
+        "simplereading_upstream_requests_total",
+        "counter",
+        "Requests sent to upstream sites, by host and status.",
+    ),
+    (
+        "simplereading_upstream_duration_seconds",
+        "histogram",
+        "Time until an upstream's response headers arrive, by host.",
+    ),
+    (
+        "simplereading_chapter_pages",
+        "histogram",
+        "Pages fetched per chapter, continuation pages included.",
+    ),
+    (
+        "simplereading_tts_chunk_duration_seconds",
+        "histogram",
+        "Time to synthesize one chunk of a chapter.",
+    ),
+    (
+        "simplereading_tts_chunk_failures_total",
+        "counter",
+        "Chunks of a chapter that failed to synthesize.",
+    ),
+    (
+        "simplereading_tts_rejected_total",
+        "counter",
+        "Speech requests refused, by the limit they hit.",
+    ),
+    (
+        "simplereading_cache_requests_total",
+        "counter",
+        "Cache lookups, by cache and result.",
+    ),
+    (
+        "simplereading_compress_input_bytes_total",
+        "counter",
+        "Bytes handed to compress_body, by the algorithm chosen.",
+    ),
+    (
+        "simplereading_compress_output_bytes_total",
+        "counter",
+        "Bytes compress_body produced, by algorithm.",
+    ),
+    (
+        "simplereading_compress_ratio",
+        "gauge",
+        "Compressed over uncompressed bytes so far, by algorithm.",
+    ),
+];
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (count, le) in self.counts.iter_mut().zip(self.buckets) {
+            if value <= *le {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Values by metric name and formatted labels
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, String), f64>,
+    histograms: BTreeMap<(&'static str, String), Histogram>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Mutex::default);
+
+// Upstream hosts reported by name. Hosts readers point ?dest= at share
+// "other", or every site they read would add a series.
+static HOSTS: OnceLock<HashSet<String>> = OnceLock::new();
+
+// The configured upstreams' hosts, set once at startup
+pub fn set_hosts(hosts: impl IntoIterator<Item = String>) {
+    let _ = HOSTS.set(hosts.into_iter().collect());
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', r"\\")
+                .replace('"', "\\\"")
+                .replace('\n', r"\n");
+            format!("{k}=\"{v}\"")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn add(name: &'static str, pairs: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.counters.entry((name, labels(pairs))).or_default() += value;
+}
+
+fn observe(name: &'static str, buckets: &'static [f64], pairs: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .histograms
+        .entry((name, labels(pairs)))
+        .or_insert_with(|| Histogram::new(buckets))
+        .observe(value);
+}
+
+// A request we answered; `route` is reader, listen, proxy and so on
+pub fn request(route: &str, status: u16, elapsed: Duration) {
+    let status = status.to_string();
+    add(
+        "simplereading_requests_total",
+        &[("route", route), ("status", &status)],
+        1.0,
+    );
+    observe(
+        "simplereading_request_duration_seconds",
+        SECONDS,
+        &[("route", route)],
+        elapsed.as_secs_f64(),
+    );
+}
+
+// A request to an upstream; `status` is the HTTP status, or why there's none
+pub fn upstream(host: &str, status: &str, elapsed: Duration) {
+    let host = match HOSTS.get() {
+        Some(hosts) if hosts.contains(host) => host,
+        _ => "other",
+    };
+    add(
+        "simplereading_upstream_requests_total",
+        &[("host", host), ("status", status)],
+        1.0,
+    );
+    observe(
+        "simplereading_upstream_duration_seconds",
+        SECONDS,
+        &[("host", host)],
+        elapsed.as_secs_f64(),
+    );
+}
+
+pub fn chapter_pages(pages: usize) {
+    observe("simplereading_chapter_pages", PAGES, &[], pages as f64);
+}
+
+pub fn tts_chunk(elapsed: Duration, ok: bool) {
+    observe(
+        "simplereading_tts_chunk_duration_seconds",
+        SECONDS,
+        &[],
+        elapsed.as_secs_f64(),
+    );
+    if !ok {
+        add("simplereading_tts_chunk_failures_total", &[], 1.0);
+    }
+}
+
+// A speech request refused for `limit`: chars, listens or connections
+pub fn tts_rejected(limit: &str) {
+    add("simplereading_tts_rejected_total", &[("limit", limit)], 1.0);
+}
+
+// A lookup in one of our caches; `result` is hit, miss or revalidated
+pub fn cache(cache: &str, result: &str) {
+    add(
+        "simplereading_cache_requests_total",
+        &[("cache", cache), ("result", result)],
+        1.0,
+    );
+}
+
+pub fn compressed(algorithm: &str, input: usize, output: usize) {
+    let pairs = [("algorithm", algorithm)];
+    add(
+        "simplereading_compress_input_bytes_total",
+        &pairs,
+        input as f64,
+    );
+    add(
+        "simplereading_compress_output_bytes_total",
+        &pairs,
+        output as f64,
+    );
+}
+
+// Everything in the Prometheus text exposition format
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let series = |name: &str, labels: &str| {
+        if labels.is_empty() {
+            name.to_string()
+        } else {
+            format!("{name}{{{labels}}}")
+        }
+    };
+    let mut out = String::new();
+    for &(name, kind, help) in METRICS {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for ((n, labels), value) in &registry.counters {
+            if *n == name {
+                let _ = writeln!(out, "{} {value}", series(name, labels));
+            }
+        }
+        if name == "simplereading_compress_ratio" {
+            for ((n, labels), input) in &registry.counters {
+                let output = registry
+                    .counters
+                    .get(&("simplereading_compress_output_bytes_total", labels.clone()));
+                if *n == "simplereading_compress_input_bytes_total"
+                    && let Some(output) = output.filter(|_| *input > 0.0)
+                {
+                    let _ = writeln!(out, "{} {}", series(name, labels), output / input);
+                }
+            }
+        }
+        for ((n, labels), h) in &registry.histograms {
+            if *n != name {
+                continue;
+            }
+            let sep = if labels.is_empty() { "" } else { "," };
+            for (le, count) in h.buckets.iter().zip(&h.counts) {
+                let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {count}");
+            }
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}", h.count);
+            let _ = writeln!(out, "{} {}", series(&format!("{name}_sum"), labels), h.sum);
+            let _ = writeln!(
+                out,
+                "{} {}",
+                series(&format!("{name}_count"), labels),
+                h.count
+            );
+        }
+    }
+    out
+}
+
+pub fn respond(req_headers: &HeaderMap) -> Result<Response<Body>> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+    );
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    let text = utils::encode_body(req_headers, &mut headers, render().into_bytes())?;
+    let mut resp = Response::new(body::full(text));
+    *resp.headers_mut() = headers;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        request("test", 200, Duration::from_millis(30));
+        request("test", 200, Duration::from_secs(3));
+        set_hosts(["a\"b.test".to_string()]);
+        upstream("a\"b.test", "502", Duration::from_millis(1));
+        upstream("reader.test", "200", Duration::from_millis(1));
+        chapter_pages(3);
+        compressed("test", 1000, 250);
+
+        let text = render();
+        assert!(text.contains("# TYPE simplereading_requests_total counter\n"));
+        assert!(text.contains("simplereading_requests_total{route=\"test\",status=\"200\"} 2\n"));
+        assert!(text.contains(
+            "simplereading_request_duration_seconds_bucket{route=\"test\",le=\"0.05\"} 1\n"
+        ));
+        assert!(text.contains(
+            "simplereading_request_duration_seconds_bucket{route=\"test\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains("simplereading_request_duration_seconds_count{route=\"test\"} 2\n"));
+        assert!(text.contains(
+            "simplereading_upstream_requests_total{host=\"a\\\"b.test\",status=\"502\"} 1\n"
+        ));
+        let other = "simplereading_upstream_requests_total{host=\"other\",status=\"200\"} 1\n";
+        assert!(text.contains(other));
+        assert!(text.contains("simplereading_chapter_pages_bucket{le=\"3\"} "));
+        assert!(text.contains("simplereading_compress_ratio{algorithm=\"test\"} 0.25\n"));
+    }
+}
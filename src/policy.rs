@@ -0,0 +1,252 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use log::warn;
+use url::Url;
+
+use crate::connect::NoProxy;
+use crate::error::AppError;
+use crate::{config, utils};
+
+// Addresses that aren't on the public internet: this host, private and
+// shared networks, link-local (cloud metadata services live there),
+// multicast and reserved ranges
+const NON_PUBLIC: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+// Which addresses outbound connections may go to. The connector checks
+// each address it resolves before dialling it, so the address checked is
+// the address connected to.
+#[derive(Debug)]
+pub struct AddressFilter {
+    block_private: bool,
+    deny: NoProxy,
+    check_deny: bool,
+    // Hosts and ports connected to wherever they are: the upstream sites,
+    // their search pages and the speech service
+    trusted: NoProxy,
+    non_public: NoProxy,
+}
+
+impl AddressFilter {
+    pub fn new(config: &config::Fetch, upstreams: &[config::Upstream]) -> Self {
+        let trusted: Vec<String> = upstreams
+            .iter()
+            .flat_map(|u| [Some(&u.url), u.search.as_ref().map(|s| &s.url)])
+            .flatten()
+            .map(String::as_str)
+            .chain([utils::ENDPOINT2])
+            .filter_map(|url| {
+                let url = Url::parse(url).ok()?;
+                Some(format!(
+                    "{}:{}",
+                    url.host_str()?,
+                    url.port_or_known_default()?
+                ))
+            })
+            .collect();
+        AddressFilter {
+            block_private: config.block_private,
+            deny: NoProxy::parse(&config.deny_hosts.join(",")),
+            check_deny: !config.deny_hosts.is_empty(),
+            trusted: NoProxy::parse(&trusted.join(",")),
+            non_public: NoProxy::parse(&NON_PUBLIC.join(",")),
+        }
+    }
+
+    pub fn trusts(&self, host: &str, port: u16) -> bool {
+        self.trusted.matches(host, port)
+    }
+
+    // Whether connections to host:port have their addresses checked
+    pub fn checks(&self, host: &str, port: u16) -> bool {
+        (self.block_private || self.check_deny) && !self.trusts(host, port)
+    }
+
+    // The address stays in our log: shown to the client, it would tell
+    // anyone how internal names resolve
+    fn refuse(&self, host: &str, ip: IpAddr, port: u16) -> Result<()> {
+        // ::ffff:127.0.0.1 is 127.0.0.1
+        let ip = ip.to_canonical().to_string();
+        if self.block_private && self.non_public.matches(&ip, port) {
+            warn!("refused {}:{}: at non-public address {}", host, port, ip);
+        } else if self.deny.matches(&ip, port) {
+            warn!("refused {}:{}: at {}, in fetch.deny_hosts", host, port, ip);
+        } else {
+            return Ok(());
+        }
+        Err(AppError::Forbidden {
+            url: format!("{host}:{port}"),
+            reason: "destination not allowed".to_string(),
+        }
+        .into())
+    }
+
+    // Resolve host:port, failing with AppError::Forbidden if any of its
+    // addresses may not be connected to
+    pub async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+        if addrs.is_empty() {
+            bail!("no address for {host}");
+        }
+        for addr in &addrs {
+            self.refuse(host, addr.ip(), port)?;
+        }
+        Ok(addrs)
+    }
+
+    // Check a host a proxy will resolve itself. Only addresses can be
+    // checked, so host names are refused.
+    pub fn remote(&self, host: &str, port: u16) -> Result<()> {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.refuse(host, ip, port),
+            Err(_) => Err(AppError::Forbidden {
+                url: format!("{host}:{port}"),
+                reason: "the proxy would resolve it, so its address can't be checked".to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+// Which urls the reader fetches for ?dest= and ?listen=, so they can't be
+// used to reach the machines around us. The addresses they resolve to are
+// left to the AddressFilter in the connector.
+#[derive(Debug)]
+pub struct UrlPolicy {
+    schemes: Vec<String>,
+    allow: Option<NoProxy>,
+    deny: NoProxy,
+    addresses: Arc<AddressFilter>,
+    pub max_size: usize,
+}
+
+impl UrlPolicy {
+    pub fn new(config: &config::Fetch, upstreams: &[config::Upstream]) -> Self {
+        UrlPolicy {
+            schemes: config.schemes.clone(),
+            allow: (!config.allow_hosts.is_empty())
+                .then(|| NoProxy::parse(&config.allow_hosts.join(","))),
+            deny: NoProxy::parse(&config.deny_hosts.join(",")),
+            addresses: Arc::new(AddressFilter::new(config, upstreams)),
+            max_size: config.max_size,
+        }
+    }
+
+    // The filter for the connector to check addresses with
+    pub fn addresses(&self) -> Arc<AddressFilter> {
+        self.addresses.clone()
+    }
+
+    // Fails with AppError::Forbidden unless `url` may be fetched. Call it
+    // again for each redirect.
+    pub fn check(&self, url: &str) -> Result<()> {
+        let forbidden = |reason: String| AppError::Forbidden {
+            url: url.to_string(),
+            reason,
+        };
+        let parsed = Url::parse(url).map_err(|e| forbidden(e.to_string()))?;
+        if !self.schemes.iter().any(|s| s == parsed.scheme()) {
+            return Err(forbidden(format!("scheme {} is not allowed", parsed.scheme())).into());
+        }
+        let Some(host) = parsed.host_str() else {
+            return Err(forbidden("no host".to_string()).into());
+        };
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        if self.deny.matches(host, port) {
+            return Err(forbidden(format!("{host} is in fetch.deny_hosts")).into());
+        }
+        if self.addresses.trusts(host, port) {
+            return Ok(());
+        }
+        if self.allow.as_ref().is_some_and(|a| !a.matches(host, port)) {
+            return Err(forbidden(format!("{host} is not in fetch.allow_hosts")).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forbidden(result: Result<()>) -> bool {
+        result.is_err_and(|e| matches!(e.downcast_ref(), Some(AppError::Forbidden { .. })))
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let mut config = config::Fetch {
+            deny_hosts: vec!["evil.test".to_string(), "203.0.113.0/24".to_string()],
+            ..Default::default()
+        };
+        let upstreams = config::Config::parse(
+            "[[upstream]]\nname = \"local\"\nurl = \"http://127.0.0.1:9101\"",
+        )
+        .unwrap()
+        .upstreams();
+        let policy = UrlPolicy::new(&config, &upstreams);
+        for url in [
+            "file:///etc/passwd",
+            "ftp://93.184.216.34/",
+            "https://www.evil.test/",
+            "http://203.0.113.9/",
+        ] {
+            assert!(forbidden(policy.check(url)), "{url}");
+        }
+        policy.check("https://93.184.216.34/book/1.html").unwrap();
+        policy.check("http://127.0.0.1:9101/book/1.html").unwrap();
+
+        let addresses = policy.addresses();
+        for (host, port) in [
+            ("localhost", 8080),
+            ("10.1.2.3", 80),
+            ("169.254.169.254", 80),
+            ("::ffff:127.0.0.1", 80),
+            ("fd00::1", 80),
+            ("203.0.113.9", 443),
+        ] {
+            assert!(addresses.checks(host, port));
+            assert!(
+                forbidden(addresses.resolve(host, port).await.map(drop)),
+                "{host}"
+            );
+        }
+        let err = addresses.resolve("localhost", 80).await.unwrap_err();
+        assert!(!format!("{err:#}").contains("127.0.0.1"));
+        assert!(!addresses.checks("127.0.0.1", 9101));
+        assert!(!addresses.checks("speech.platform.bing.com", 443));
+        addresses.resolve("93.184.216.34", 443).await.unwrap();
+        // Behind a proxy that resolves names, only addresses can be checked
+        addresses.remote("93.184.216.34", 443).unwrap();
+        assert!(forbidden(addresses.remote("10.0.0.1", 80)));
+        assert!(forbidden(addresses.remote("www.example.com", 443)));
+
+        config.allow_hosts = vec!["93.184.216.0/24".to_string()];
+        config.deny_hosts.clear();
+        config.block_private = false;
+        let policy = UrlPolicy::new(&config, &upstreams);
+        policy.check("http://93.184.216.34/").unwrap();
+        policy.check("http://127.0.0.1:9101/").unwrap();
+        assert!(forbidden(policy.check("http://198.51.100.1/")));
+        assert!(!policy.addresses().checks("10.1.2.3", 80));
+    }
+}
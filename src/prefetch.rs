@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{debug, info};
+use tokio::sync::OnceCell;
+use tokio::task::AbortHandle;
+
+use crate::{AppContext, Chapter, config, logging, metrics, ttslimit};
+
+type Slot<T> = Arc<OnceCell<Arc<T>>>;
+
+// A small TTL/capacity bounded map whose values are computed at most once,
+// even when a reader and a background prefetch ask for the same key together.
+struct Store<T> {
+    entries: Mutex<HashMap<String, (Instant, Slot<T>)>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl<T> Store<T> {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Store {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+        }
+    }
+
+    fn slot(&self, key: &str) -> Slot<T> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, (created, _)| now.duration_since(*created) < self.ttl);
+        if let Some((_, cell)) = entries.get(key) {
+            return cell.clone();
+        }
+        if entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (created, _))| *created)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        let cell = Arc::new(OnceCell::new());
+        entries.insert(key.to_string(), (now, cell.clone()));
+        cell
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .is_some_and(|(created, cell)| cell.initialized() && created.elapsed() < self.ttl)
+    }
+
+    async fn get_or_try_init<F, Fut>(&self, key: &str, f: F) -> Result<Arc<T>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let cell = self.slot(key);
+        let v = cell
+            .get_or_try_init(|| async { f().await.map(Arc::new) })
+            .await?;
+        Ok(v.clone())
+    }
+}
+
+#[derive(Default)]
+struct Reader {
+    // Oldest first
+    tasks: Vec<(String, AbortHandle)>,
+}
+
+// Background prefetching of the next chapter (and optionally its audio)
+pub struct Prefetcher {
+    enabled: bool,
+    audio: bool,
+    per_user: usize,
+    pages: Store<Chapter>,
+    mp3s: Store<Vec<u8>>,
+    readers: Mutex<HashMap<IpAddr, Reader>>,
+}
+
+impl std::fmt::Debug for Prefetcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Prefetcher")
+            .field("enabled", &self.enabled)
+            .field("audio", &self.audio)
+            .field("per_user", &self.per_user)
+            .field("capacity", &self.pages.capacity)
+            .field("ttl", &self.pages.ttl)
+            .finish()
+    }
+}
+
+impl Prefetcher {
+    pub fn new(config: &config::Cache) -> Self {
+        let ttl = Duration::from_secs(config.ttl);
+        Prefetcher {
+            enabled: config.prefetch,
+            audio: config.prefetch_audio,
+            per_user: config.prefetch_per_user,
+            pages: Store::new(config.entries, ttl),
+            mp3s: Store::new(config.entries.div_ceil(4), ttl),
+            readers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Get a chapter from the cache, fetching it if it is not there yet
+    pub async fn chapter(&self, context: &AppContext, url: &str) -> Result<Arc<Chapter>> {
+        let hit = self.pages.contains(url);
+        metrics::cache("chapter", if hit { "hit" } else { "miss" });
+        logging::note_cache(if hit { "hit" } else { "miss" });
+        debug!(
+            "chapter cache {}: {}",
+            if hit { "hit" } else { "miss" },
+            url
+        );
+        self.load_chapter(context, url).await
+    }
+
+    // Get the audio of a chapter from the cache, synthesizing it on
+    // `listener`'s allowance if needed
+    pub async fn mp3(
+        &self,
+        context: &AppContext,
+        listener: &str,
+        url: &str,
+    ) -> Result<Arc<Vec<u8>>> {
+        let hit = self.mp3s.contains(url);
+        metrics::cache("audio", if hit { "hit" } else { "miss" });
+        logging::note_cache(if hit { "hit" } else { "miss" });
+        if !hit {
+            let chapter = self.load_chapter(context, url).await?;
+            context
+                .tts_limit
+                .charge(listener, ttslimit::chars(&chapter.text))?;
+        }
+        self.load_mp3(context, url).await
+    }
+
+    // The lookups behind chapter() and mp3(), which prefetches use so they
+    // don't count as readers missing the cache
+    async fn load_chapter(&self, context: &AppContext, url: &str) -> Result<Arc<Chapter>> {
+        self.pages
+            .get_or_try_init(url, || crate::get_all_txt(context, url.to_string()))
+            .await
+    }
+
+    async fn load_mp3(&self, context: &AppContext, url: &str) -> Result<Arc<Vec<u8>>> {
+        let chapter = self.load_chapter(context, url).await?;
+        self.mp3s
+            .get_or_try_init(url, || crate::synthesize(context, chapter.text.clone()))
+            .await
+    }
+
+    // Warm the cache for the chapter after `chapter` on behalf of `client`.
+    // Each client has at most `per_user` prefetches running; past that the
+    // oldest, for a chapter the client has moved on from, is cancelled.
+    // Audio is synthesized on `listener`'s allowance.
+    pub fn schedule(
+        &self,
+        context: Arc<AppContext>,
+        client: IpAddr,
+        listener: String,
+        chapter: &Chapter,
+    ) {
+        let Some(next) = chapter.next.clone() else {
+            return;
+        };
+        if !self.enabled || self.per_user == 0 || (self.pages.contains(&next) && !self.audio) {
+            return;
+        }
+        let mut readers = self.readers.lock().unwrap();
+        readers.retain(|ip, r| {
+            r.tasks.retain(|(_, h)| !h.is_finished());
+            *ip == client || !r.tasks.is_empty()
+        });
+        let reader = readers.entry(client).or_default();
+        if reader.tasks.iter().any(|(url, _)| *url == next) {
+            return;
+        }
+        while reader.tasks.len() >= self.per_user {
+            let (url, h) = reader.tasks.remove(0);
+            debug!("cancel prefetch for {}: {}", client, url);
+            h.abort();
+        }
+        info!("prefetch for {}: {}", client, &next);
+        let url = next.clone();
+        let handle = tokio::spawn(logging::inherit(async move {
+            let prefetcher = &context.prefetch;
+            let chapter = match prefetcher.load_chapter(&context, &url).await {
+                Ok(chapter) => chapter,
+                Err(e) => {
+                    info!("prefetch {} failed: {}", &url, e);
+                    return;
+                }
+            };
+            if !prefetcher.audio || prefetcher.mp3s.contains(&url) {
+                return;
+            }
+            if let Err(e) = context
+                .tts_limit
+                .charge(&listener, ttslimit::chars(&chapter.text))
+            {
+                debug!("skip prefetching audio {}: {}", &url, e);
+                return;
+            }
+            if let Err(e) = prefetcher.load_mp3(&context, &url).await {
+                info!("prefetch audio {} failed: {}", &url, e);
+            }
+        }));
+        reader.tasks.push((next, handle.abort_handle()));
+    }
+
+    // Stop all background prefetches, e.g. when shutting down
+    pub fn cancel(&self) {
+        let mut readers = self.readers.lock().unwrap();
+        let mut n = 0;
+        for reader in readers.values() {
+            for (url, h) in &reader.tasks {
+                if !h.is_finished() {
+                    debug!("cancel prefetch: {}", url);
+                    h.abort();
+                    n += 1;
+                }
+            }
+        }
+        readers.clear();
+        if n > 0 {
+            info!("cancelled {} prefetch(es)", n);
+        }
+    }
+}
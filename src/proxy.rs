@@ -1,45 +1,45 @@
-use std::io::Read;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
-use http_body_util::{BodyExt, Full};
-use hyper::body::{Bytes, Incoming};
-use hyper::header::{HeaderMap, HeaderValue};
-use hyper::{Request, Response, Uri};
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use anyhow::Result;
+use encoding_rs::Encoding;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Body as _, Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode, Uri};
 use log::{debug, info};
 use std::sync::LazyLock;
+use url::Url;
 
-use crate::{utils, AppContext};
+use crate::body::{self, Body, Page, PageDecoder};
+use crate::error::AppError;
+use crate::sites::Site;
+use crate::{cookies, httpcache, logging, metrics, utils, AppContext};
 
-async fn modify_response(
-    context: Arc<AppContext>,
-    req_headers: &HeaderMap,
-    // forward_uri: &str,
-    resp: Response<Full<Bytes>>,
-) -> Result<Response<Full<Bytes>>> {
-    let mut res = Response::builder().status(resp.status());
-    info!("modify response");
-    debug!("status: {}", resp.status());
-    let new_headers = res.headers_mut().context("failed to get headers")?;
-    let accept_encoding = req_headers
-        .get(hyper::header::ACCEPT_ENCODING)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-    let mut need_compress = true;
-    let mut compression_type = accept_encoding.to_string();
-    for (key, value) in resp.headers().iter() {
-        let mut text = value.to_str()?.to_string();
+// Rewrite the headers of an upstream response for the reader
+fn rewrite_headers(
+    context: &AppContext,
+    site: &Site,
+    url: &Url,
+    host: &str,
+    headers: &HeaderMap,
+) -> Result<HeaderMap> {
+    let mut new_headers = HeaderMap::new();
+    let rules = context.rewrite.rules();
+    for (key, value) in headers.iter() {
+        let mut text = rules.header(url, key, value.to_str()?.to_string());
         if key == hyper::header::SET_COOKIE {
-            text = text.replace(".booklink.me", &context.host);
+            let secure = context.scheme == "https";
+            let Some(cookie) = cookies::rewrite(&text, site, host, secure) else {
+                continue;
+            };
+            text = cookie;
             debug!("set cookie: {}", text);
         }
         if key == hyper::header::LOCATION {
-            need_compress = false;
-            text = text.replace("http://www.wcxsw.org/", "https://m.wcxsw.org/");
-            text = text.replace("http://www.wucuoxs.com", "https://m.wucuoxs.com");
-            if text.starts_with("http") && !text.contains(&context.host) {
+            if let Some(local) = site.mount_location(&text) {
+                text = local;
+            } else if text.starts_with("http") && !text.contains(&context.host) {
                 if !context.port.is_empty() {
                     text = format!(
                         "{}://{}:{}/?dest={}",
@@ -54,92 +54,121 @@ async fn modify_response(
         }
         new_headers.append(key, text.as_str().parse()?);
     }
-    let mut body_bytes: Vec<u8> = resp.collect().await?.to_bytes().to_vec();
-    if let Some(v) = new_headers.get(hyper::header::CONTENT_TYPE) {
-        if v.to_str()?.contains("text") {
-            let mut body_string = String::from_utf8(body_bytes)?;
-            body_string = body_string.replace("google-analytics.com", "0.0.0.0");
-            body_string = body_string.replace("adsbygoogle", "xxxxxxx");
-            body_string = body_string.replace("<li class=\"hla\">", "<li class=\"\">");
-            if body_string.contains("slist sec") {
-                body_string = body_string.replace(
-                    "<body>",
-                    "<body><style>ul.list.sec {display: none;}</style>",
-                );
-            }
-            body_string =
-                body_string.replace("www.google.com/search?ie=utf-8&", "kagi.com/search?");
-            let script = r#"
-                <script type="text/javascript">
-                function chg(e) {
-                    if (e === "dark") {
-                        document.body.style.color = "white";
-                        document.body.style.backgroundColor = "black";
-                        var all = document.querySelectorAll('a');
-                        var top = document.querySelectorAll('a.top');
-                        [].slice.call(all).forEach(function(elem) {
-                            elem.style.color = '#338dff';
-                        });
-                        [].slice.call(top).forEach(function(elem) {
-                            elem.style.color = '#f00';
-                        });
-                        var elements = document.getElementsByClassName('grey');
-                        [].slice.call(elements).forEach(function(elem) {
-                            elem.style.color = '#a9a196';
-                        });
-                    } else {
-                        document.body.style.color = "black";
-                        document.body.style.backgroundColor = "white";
-                        var all = document.querySelectorAll('a');
-                        var top = document.querySelectorAll('a.top');
-                        [].slice.call(all).forEach(function(elem) {
-                            elem.style.color = '#03f';
-                        });
-                        [].slice.call(top).forEach(function(elem) {
-                            elem.style.color = '#f00';
-                        });
-                        var elements = document.getElementsByClassName('grey');
-                        [].slice.call(elements).forEach(function(elem) {
-                            elem.style.color = '#646464';
-                        });
-                    }
-                }
-                if (window.matchMedia && window.matchMedia('(prefers-color-scheme: dark)').matches) {
-                    chg("dark");
-                } else {
-                    chg("light");
-                }
-                window.matchMedia('(prefers-color-scheme: dark)').addEventListener('change', event => {
-                    const newColorScheme = event.matches ? "dark" : "light";
-                    chg(newColorScheme);
-                });
-                </script>
-                </body>
-                "#;
-            // body_string = body_string.replace("color=\"#646464\"", "color=\"#a9a196\"");
-            body_string =
-                body_string.replace("><font color=\"#646464\">", " class=\"grey\"><font>");
-            body_string = body_string.replace("</body>", script);
-            body_bytes = body_string.as_bytes().to_vec();
+    Ok(new_headers)
+}
+
+// The charset a Content-Type names
+fn charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|p| {
+        let (k, v) = p.split_once('=')?;
+        if !k.trim().eq_ignore_ascii_case("charset") {
+            return None;
         }
+        Encoding::for_label(v.trim().trim_matches('"').as_bytes())
+    })
+}
+
+// Content encodings body::PageDecoder undoes
+const DECODABLE: [&str; 5] = ["gzip", "x-gzip", "deflate", "br", "zstd"];
+
+async fn modify_response(
+    context: Arc<AppContext>,
+    site: &Site,
+    url: &Url,
+    // The host the reader asked for
+    host: &str,
+    req_headers: &HeaderMap,
+    resp: Response<Body>,
+) -> Result<Response<Body>> {
+    info!("modify response");
+    debug!("status: {}", resp.status());
+    let (mut parts, body) = resp.into_parts();
+    remove_hop_headers(&mut parts.headers);
+    parts.headers = rewrite_headers(&context, site, url, host, &parts.headers)?;
+    if parts.status == StatusCode::OK && httpcache::not_modified(req_headers, &parts.headers) {
+        debug!("not modified: {}", url);
+        return Ok(httpcache::not_modified_response(&parts.headers));
     }
-    if need_compress {
-        body_bytes = utils::compress_body(&body_bytes, &mut compression_type)?;
 
-        new_headers.remove(hyper::header::CONTENT_ENCODING);
-        new_headers.remove(hyper::header::CONTENT_LENGTH);
-        new_headers.insert(
-            hyper::header::CONTENT_ENCODING,
-            HeaderValue::from_str(&compression_type)?,
-        );
-        debug!("compress body");
-        new_headers.insert(
-            hyper::header::CONTENT_LENGTH,
-            body_bytes.len().to_string().parse()?,
-        );
-        debug!("set content length: {}", body_bytes.len());
+    let content_type = parts
+        .headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let encoding = parts
+        .headers
+        .get(hyper::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let html =
+        content_type.starts_with("text/html") || content_type.starts_with("application/xhtml+xml");
+    let rules = context.rewrite.rules();
+    // Assets, redirects and bodies we can't decode go through as they are
+    if body.is_end_stream()
+        || parts.headers.contains_key(hyper::header::LOCATION)
+        || !(html || rules.wants_body(url, &content_type))
+        || !(encoding.is_empty()
+            || encoding == "identity"
+            || DECODABLE.contains(&encoding.as_str()))
+    {
+        debug!("stream {} ({})", url, content_type);
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    // Pages without a charset are usually GB18030 on these sites
+    let default = if html {
+        encoding_rs::GB18030
+    } else {
+        encoding_rs::UTF_8
+    };
+    let charset = charset(&content_type).unwrap_or(default);
+    debug!("decoding {} {}", encoding, charset.name());
+    let limit = context.proxy.max_body;
+    let decoder = PageDecoder::new(&encoding, charset, limit)?;
+    let read_timeout = context.client.read_timeout();
+    let mut text = match body::read_page(url.as_str(), body, decoder, limit, read_timeout).await? {
+        Page::Text(text) => text,
+        Page::TooLarge(seen, rest) => {
+            info!("{} is over {} bytes, passing it through", url, limit);
+            return Ok(Response::from_parts(parts, body::resume(seen, rest)));
+        }
+    };
+
+    if html {
+        for r in &site.replace {
+            text = text.replace(&r.from, &r.to);
+        }
+    }
+    text = rules.body(url, &content_type, text);
+    if html {
+        if let Some(adblock) = &context.adblock {
+            text = adblock.clean(url, &text)?;
+        }
+        text = site.mount_links(&text);
+        text = text.replace("</body>", &format!("{}</body>", site.inject));
     }
-    Ok(res.body(Full::from(body_bytes))?)
+
+    // The page no longer matches the upstream's bytes, only its meaning
+    if let Some(etag) = parts
+        .headers
+        .get(hyper::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|e| !e.starts_with("W/"))
+    {
+        let weak = HeaderValue::from_str(&format!("W/{etag}"))?;
+        parts.headers.insert(hyper::header::ETAG, weak);
+    }
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    parts.headers.insert(
+        hyper::header::CONTENT_TYPE,
+        HeaderValue::from_str(&format!("{mime}; charset=utf-8"))?,
+    );
+    let body_bytes = utils::encode_body(req_headers, &mut parts.headers, text.into_bytes())?;
+    debug!("encoded body: {} bytes", body_bytes.len());
+    Ok(Response::from_parts(parts, body::full(body_bytes)))
 }
 
 fn is_hop_header(name: &str) -> bool {
@@ -175,102 +204,52 @@ fn remove_hop_headers(headers: &mut HeaderMap<HeaderValue>) {
     }
 }
 
-async fn create_proxied_response(
-    mut response: Response<Incoming>,
-) -> Result<Response<Full<Bytes>>> {
-    // println!("original response: {:#?}", response);
-    info!("create_proxied_response");
-    remove_hop_headers(response.headers_mut());
-    let (mut parts, body) = response.into_parts();
-    let body_bytes = body.collect().await?.to_bytes().to_vec();
-    let headers = parts.headers.clone();
-    let decoder = headers
-        .get(hyper::header::CONTENT_ENCODING)
-        .and_then(|value| {
-            parts.headers.remove(hyper::header::CONTENT_ENCODING);
-            value.to_str().ok()
+// What the client accepts, less what we couldn't decode for rewriting.
+// Streamed responses then reach the client in an encoding it understands.
+fn upstream_accept_encoding(headers: &HeaderMap) -> HeaderValue {
+    let accepted: Vec<&str> = headers
+        .get_all(hyper::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let refused = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            DECODABLE.iter().any(|d| d.eq_ignore_ascii_case(name)) && !refused
         })
-        .unwrap_or_default();
-    debug!("decoder: {}", &decoder);
-    let mut decoded = match decoder {
-        "gzip" => {
-            let mut decoder = flate2::read::GzDecoder::new(&body_bytes[..]);
-            let mut buf = Vec::new();
-            let _ = decoder.read_to_end(&mut buf);
-            buf
-        }
-        "deflate" => {
-            let mut decoder = flate2::read::DeflateDecoder::new(&body_bytes[..]);
-            let mut buf = Vec::new();
-            let _ = decoder.read_to_end(&mut buf);
-            buf
-        }
-        "br" => {
-            let mut decoder = brotli::Decompressor::new(&body_bytes[..], body_bytes.len());
-            let mut buf = Vec::new();
-            let _ = decoder.read_to_end(&mut buf);
-            buf
-        }
-        "zstd" => {
-            let mut decoder = zstd::Decoder::new(&body_bytes[..])?;
-            let mut buf = Vec::new();
-            let _ = decoder.read_to_end(&mut buf);
-            buf
-        }
-        _ => body_bytes.to_vec(),
-    };
-
-    // Convert the response body to UTF-8 encoding if it is an HTML document and not already UTF-8
-    let content_type = headers
-        .get(hyper::header::CONTENT_TYPE)
-        .context("get content type error")?;
-    let v = content_type.to_str()?;
-    if v.contains("text/html") {
-        let mut encoding = "GB18030";
-        if v.contains("charset=") {
-            let x = v.split("charset=").collect::<Vec<&str>>();
-            let y = x[1];
-            parts.headers.insert(
-                hyper::header::CONTENT_TYPE,
-                format!("{}charset=utf-8", x[0].to_owned()).parse()?,
-            );
-            encoding = y.trim();
-        }
-        let e = &encoding.to_lowercase();
-        debug!("encoding: {}", &e);
-        decoded = utils::to_utf8(&decoded, e)?.as_bytes().to_vec();
-    }
-    Ok(Response::from_parts(parts, decoded.into()))
-}
-
-fn forward_uri(forward_url: &str, req: &Request<Incoming>) -> Result<Uri> {
-    if !forward_url.is_empty() {
-        let new_uri = if let Some(query) = req.uri().query() {
-            format!("{}{}?{}", forward_url, req.uri().path(), query)
-        } else {
-            format!("{}{}", forward_url, req.uri().path())
-        };
-        Ok(Uri::from_str(new_uri.as_str())?)
+        .collect();
+    if accepted.is_empty() {
+        HeaderValue::from_static("identity")
     } else {
-        Ok(req.uri().clone())
+        HeaderValue::from_str(&accepted.join(", ")).unwrap_or(HeaderValue::from_static("identity"))
     }
 }
 
-fn create_proxied_request(
+async fn create_proxied_request(
     context: Arc<AppContext>,
-    forward_url: &str,
+    site: &Site,
     mut request: Request<Incoming>,
-) -> Result<Request<Incoming>> {
+) -> Result<Request<Full<Bytes>>> {
     remove_hop_headers(request.headers_mut());
-    *request.uri_mut() = forward_uri(forward_url, &request)?;
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    *request.uri_mut() = Uri::from_str(&site.forward(path))?;
 
     let host_val = request.uri().host().unwrap().to_string();
-    request.headers_mut().remove(hyper::header::ACCEPT_ENCODING);
-
-    request.headers_mut().insert(
-        hyper::header::ACCEPT_ENCODING,
-        HeaderValue::from_static("gzip, deflate, br, zstd"),
-    );
+    let accept_encoding = upstream_accept_encoding(request.headers());
+    request
+        .headers_mut()
+        .insert(hyper::header::ACCEPT_ENCODING, accept_encoding);
     request
         .headers_mut()
         .insert(hyper::header::HOST, host_val.parse()?);
@@ -278,24 +257,128 @@ fn create_proxied_request(
     request
         .headers_mut()
         .insert(hyper::header::USER_AGENT, context.ua.parse()?);
-    Ok(request)
+    let (parts, body) = request.into_parts();
+    // Buffered so a retry can send it again, which bounds it in size and time
+    let max = context.proxy.max_request_body;
+    let body = tokio::time::timeout(
+        context.client.read_timeout(),
+        Limited::new(body, max).collect(),
+    )
+    .await
+    .map_err(|_| AppError::RequestTimeout)?
+    .map_err(|e| match e.downcast::<LengthLimitError>() {
+        Ok(_) => AppError::RequestTooLarge { max }.into(),
+        Err(e) => anyhow::anyhow!(e),
+    })?
+    .to_bytes();
+    Ok(Request::from_parts(parts, Full::new(body)))
 }
 
+// Conditional request headers, which we answer from the cache ourselves
+const CONDITIONALS: [HeaderName; 4] = [
+    hyper::header::IF_NONE_MATCH,
+    hyper::header::IF_MODIFIED_SINCE,
+    hyper::header::IF_MATCH,
+    hyper::header::IF_UNMODIFIED_SINCE,
+];
+
 pub async fn call(
     context: Arc<AppContext>,
-    forward_uri: &str,
-    request: Request<Incoming>,
-) -> Result<Response<Full<Bytes>>> {
+    site: &Site,
+    mut request: Request<Incoming>,
+) -> Result<Response<Body>> {
+    // Our own credentials are neither sent upstream nor part of what the
+    // cache sees
+    context.auth.strip(request.headers_mut());
     let req_headers = request.headers().clone();
-    let proxied_request = create_proxied_request(context.clone(), forward_uri, request)?;
-    let https = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_webpki_roots()
-        .https_or_http()
-        .enable_http1()
-        .enable_http2()
-        .build();
-    let client = Client::builder(TokioExecutor::new()).build(https);
-    let response = client.request(proxied_request).await?;
-    let proxied_response = create_proxied_response(response).await?;
-    modify_response(context.clone(), &req_headers, proxied_response).await
+    let host = crate::request_host(&request)
+        .unwrap_or(&context.host)
+        .to_string();
+    let cache = context
+        .http_cache
+        .clone()
+        .filter(|_| request.method() == Method::GET);
+    let mut proxied_request = create_proxied_request(context.clone(), site, request).await?;
+    let url = Url::parse(&proxied_request.uri().to_string())?;
+    debug!("proxy to {}: {}", site.name, &url);
+
+    let mut stored = None;
+    if let Some(cache) = &cache {
+        for name in &CONDITIONALS {
+            proxied_request.headers_mut().remove(name);
+        }
+        if let Some(entry) = cache.lookup(url.as_str(), &req_headers) {
+            if entry.is_fresh() && !httpcache::wants_revalidation(&req_headers) {
+                debug!("cache hit: {}", url);
+                metrics::cache("http", "hit");
+                logging::note_cache("hit");
+                let resp = entry.response();
+                return modify_response(context.clone(), site, &url, &host, &req_headers, resp)
+                    .await;
+            }
+            entry.add_validators(proxied_request.headers_mut());
+            stored = Some(entry);
+        }
+    }
+
+    let mut response = context.client.request(proxied_request).await?;
+    remove_hop_headers(response.headers_mut());
+    if cache.is_some() {
+        let revalidated = stored.is_some() && response.status() == StatusCode::NOT_MODIFIED;
+        let result = if revalidated { "revalidated" } else { "miss" };
+        metrics::cache("http", result);
+        logging::note_cache(result);
+    }
+    let response = match (cache, stored) {
+        (Some(cache), Some(entry)) if response.status() == StatusCode::NOT_MODIFIED => {
+            debug!("cache revalidated: {}", url);
+            cache
+                .refresh(url.as_str(), &entry, response.headers())
+                .response()
+        }
+        (Some(cache), _) if cache.storable(&req_headers, response.status(), response.headers()) => {
+            let (parts, incoming) = response.into_parts();
+            let (key, req, status, headers) = (
+                url.to_string(),
+                req_headers.clone(),
+                parts.status,
+                parts.headers.clone(),
+            );
+            let limit = cache.max_entry();
+            let body = body::tee(body::stream(incoming), limit, move |bytes| {
+                cache.insert(&key, &req, status, headers, bytes);
+            });
+            Response::from_parts(parts, body)
+        }
+        _ => response.map(body::stream),
+    };
+    modify_response(context.clone(), site, &url, &host, &req_headers, response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let headers = |v: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(hyper::header::ACCEPT_ENCODING, v.parse().unwrap());
+            h
+        };
+        assert_eq!(
+            upstream_accept_encoding(&headers("gzip, deflate, br, zstd, dcb, dcz")),
+            "gzip, deflate, br, zstd"
+        );
+        assert_eq!(
+            upstream_accept_encoding(&headers("br;q=1.0, gzip;q=0, *")),
+            "br;q=1.0"
+        );
+        assert_eq!(upstream_accept_encoding(&HeaderMap::new()), "identity");
+        assert_eq!(
+            charset("text/html; Charset=\"GBK\""),
+            Some(encoding_rs::GBK)
+        );
+        assert_eq!(charset("text/html"), None);
+    }
 }
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use hyper::header::HeaderValue;
+use log::debug;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc2822;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config;
+
+// Slowest rate accepted, a request about every 17 minutes; slower ones are
+// more likely typos, and make waits overflow
+const MIN_RATE: f64 = 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    // Requests per second allowed on average
+    pub per_sec: f64,
+    // Requests allowed in a burst
+    pub burst: f64,
+    // Requests in flight at once
+    pub concurrency: usize,
+}
+
+impl Rate {
+    // "rate[:burst[:concurrency]]", e.g. "0.5" or "2:4:2"
+    fn parse(s: &str, default: Rate) -> Result<Rate> {
+        let mut parts = s.split(':').map(str::trim);
+        let per_sec: f64 = parts.next().context("empty rate")?.parse()?;
+        anyhow::ensure!(
+            per_sec >= MIN_RATE,
+            "rate must be at least {}: {}",
+            MIN_RATE,
+            s
+        );
+        let burst = match parts.next() {
+            Some(b) => b.parse()?,
+            None => default.burst,
+        };
+        let concurrency = match parts.next() {
+            Some(c) => c.parse()?,
+            None => default.concurrency,
+        };
+        Ok(Rate {
+            per_sec,
+            burst: f64::max(burst, 1.0),
+            concurrency: concurrency.max(1),
+        })
+    }
+}
+
+struct Host {
+    rate: Rate,
+    permits: Arc<Semaphore>,
+    // (tokens, last refill, not before)
+    bucket: Mutex<(f64, Instant, Instant)>,
+}
+
+impl Host {
+    // Take a token, returning how long to wait before it may be used
+    fn take(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last, not_before) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = f64::min(
+            self.rate.burst,
+            *tokens + now.duration_since(*last).as_secs_f64() * self.rate.per_sec,
+        );
+        *last = now;
+        *tokens -= 1.0;
+        let wait = if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-*tokens / self.rate.per_sec).unwrap_or(Duration::MAX)
+        };
+        wait.max(not_before.saturating_duration_since(now))
+    }
+
+    // Whether forgetting the host changes nothing: no requests in flight,
+    // a full bucket and no back-off
+    fn idle(&self, now: Instant) -> bool {
+        let (tokens, last, not_before) = *self.bucket.lock().unwrap();
+        self.permits.available_permits() == self.rate.concurrency
+            && tokens + now.duration_since(last).as_secs_f64() * self.rate.per_sec
+                >= self.rate.burst
+            && not_before <= now
+    }
+}
+
+// Per-host token buckets with concurrency caps and jitter, shared by every
+// outbound request so several readers can't get us banned upstream.
+pub struct Limiter {
+    default: Rate,
+    overrides: Vec<(String, Rate)>,
+    jitter: Duration,
+    pub max_retries: usize,
+    pub max_retry_after: Duration,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+impl std::fmt::Debug for Limiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Limiter")
+            .field("default", &self.default)
+            .field("overrides", &self.overrides)
+            .field("jitter", &self.jitter)
+            .field("max_retries", &self.max_retries)
+            .finish()
+    }
+}
+
+impl Limiter {
+    pub fn new(config: &config::RateLimit) -> Result<Self> {
+        let builtin = Rate {
+            per_sec: 2.0,
+            burst: 4.0,
+            concurrency: 4,
+        };
+        let default = Rate::parse(&config.rate, builtin).context("bad rate")?;
+        let overrides = config
+            .hosts
+            .iter()
+            .map(|(host, rate)| {
+                let rate =
+                    Rate::parse(rate, default).with_context(|| format!("bad rate for {host}"))?;
+                Ok((host.to_ascii_lowercase(), rate))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Limiter {
+            default,
+            overrides,
+            jitter: Duration::from_millis(config.jitter_ms),
+            max_retries: config.max_retries,
+            max_retry_after: Duration::from_millis(config.max_retry_after_ms),
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn rate_for(&self, host: &str) -> Rate {
+        self.overrides
+            .iter()
+            .find(|(h, _)| host == h || host.ends_with(&format!(".{h}")))
+            .map(|(_, r)| *r)
+            .unwrap_or(self.default)
+    }
+
+    fn host(&self, host: &str) -> Arc<Host> {
+        let host = host.to_ascii_lowercase();
+        let mut hosts = self.hosts.lock().unwrap();
+        // Any host can be asked for with ?dest=, so forget idle ones
+        // before adding another
+        if !hosts.contains_key(&host) {
+            let now = Instant::now();
+            hosts.retain(|_, h| Arc::strong_count(h) > 1 || !h.idle(now));
+        }
+        hosts
+            .entry(host.clone())
+            .or_insert_with(|| {
+                let rate = self.rate_for(&host);
+                let now = Instant::now();
+                Arc::new(Host {
+                    rate,
+                    permits: Arc::new(Semaphore::new(rate.concurrency)),
+                    bucket: Mutex::new((rate.burst, now, now)),
+                })
+            })
+            .clone()
+    }
+
+    // Wait for our turn to send a request to `host`. The returned permit
+    // counts against the host's concurrency cap until it is dropped.
+    pub async fn acquire(&self, host: &str) -> OwnedSemaphorePermit {
+        let h = self.host(host);
+        let permit = h
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore never closed");
+        let mut wait = h.take();
+        if !self.jitter.is_zero() {
+            let jitter = Duration::from_millis(fastrand::u64(0..=self.jitter.as_millis() as u64));
+            wait = wait.saturating_add(jitter);
+        }
+        if wait > Duration::from_millis(1) {
+            debug!("throttle {} for {:?}", host, wait);
+            tokio::time::sleep(wait).await;
+        }
+        permit
+    }
+
+    // Hold off every request to `host` for `wait`, e.g. after a 429
+    pub fn back_off(&self, host: &str, wait: Duration) {
+        let h = self.host(host);
+        let mut bucket = h.bucket.lock().unwrap();
+        bucket.2 = bucket.2.max(Instant::now() + wait);
+    }
+}
+
+// Retry-After is either a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    let s = value.to_str().ok()?.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = OffsetDateTime::parse(s, &Rfc2822).ok()?;
+    let secs = (at - OffsetDateTime::now_utc()).whole_seconds().max(0);
+    Some(Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        let default = Rate {
+            per_sec: 2.0,
+            burst: 4.0,
+            concurrency: 4,
+        };
+        assert_eq!(Rate::parse("0.5", default).unwrap().burst, 4.0);
+        let r = Rate::parse("5:10:8", default).unwrap();
+        assert_eq!((r.per_sec, r.burst, r.concurrency), (5.0, 10.0, 8));
+        assert!(Rate::parse("0", default).is_err());
+        assert!(Rate::parse("1e-320", default).is_err());
+        assert!(Rate::parse("NaN", default).is_err());
+        assert!(Rate::parse("fast", default).is_err());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let v = HeaderValue::from_static("120");
+        assert_eq!(parse_retry_after(&v), Some(Duration::from_secs(120)));
+        let v = HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(parse_retry_after(&v), Some(Duration::ZERO));
+        let v = HeaderValue::from_static("soon");
+        assert_eq!(parse_retry_after(&v), None);
+    }
+
+    #[test]
+    fn test_bucket() {
+        let rate = Rate {
+            per_sec: 1.0,
+            burst: 2.0,
+            concurrency: 1,
+        };
+        let now = Instant::now();
+        let h = Host {
+            rate,
+            permits: Arc::new(Semaphore::new(1)),
+            bucket: Mutex::new((rate.burst, now, now)),
+        };
+        assert_eq!(h.take(), Duration::ZERO);
+        assert_eq!(h.take(), Duration::ZERO);
+        assert!(h.take() > Duration::from_millis(900));
+        assert!(!h.idle(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_forget_idle_hosts() {
+        let limiter = Limiter::new(&config::RateLimit {
+            jitter_ms: 0,
+            ..Default::default()
+        })
+        .unwrap();
+        let known = || {
+            let mut hosts: Vec<String> = limiter.hosts.lock().unwrap().keys().cloned().collect();
+            hosts.sort();
+            hosts
+        };
+        let refill = || {
+            for h in limiter.hosts.lock().unwrap().values() {
+                h.bucket.lock().unwrap().0 = h.rate.burst;
+            }
+        };
+        let _in_flight = limiter.acquire("a.example.com").await;
+        drop(limiter.acquire("b.example.com").await);
+        limiter.back_off("c.example.com", Duration::from_secs(60));
+        // b has used a token, so it is remembered until the bucket refills
+        drop(limiter.acquire("d.example.com").await);
+        assert_eq!(known().len(), 4);
+        refill();
+        drop(limiter.acquire("e.example.com").await);
+        assert_eq!(known(), ["a.example.com", "c.example.com", "e.example.com"]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use hyper::header::HeaderName;
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::Deserialize;
+use url::Url;
+
+use crate::config;
+
+const DEFAULT_RULES: &str = include_str!("../rules/default.toml");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    #[serde(default)]
+    name: String,
+    find: String,
+    #[serde(default)]
+    replace: String,
+    // `find` is a regex and `replace` may use $1, $name
+    #[serde(default)]
+    regex: bool,
+    // The page's host or a parent domain of it
+    host: Option<String>,
+    // Prefix of the page's path
+    path: Option<String>,
+    // Prefix of the response content type, for body rules; text/ by
+    // default
+    content_type: Option<String>,
+    // Rewrite this response header instead of the body
+    header: Option<String>,
+    // Only rewrite bodies that contain this
+    when: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleSpec>,
+}
+
+#[derive(Debug)]
+enum Find {
+    Literal(String),
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    find: Find,
+    replace: String,
+    host: Option<String>,
+    path: Option<String>,
+    content_type: String,
+    header: Option<HeaderName>,
+    when: Option<String>,
+}
+
+impl Rule {
+    fn new(spec: RuleSpec) -> Result<Self> {
+        let find = if spec.regex {
+            Find::Regex(Regex::new(&spec.find)?)
+        } else {
+            anyhow::ensure!(!spec.find.is_empty(), "empty find");
+            Find::Literal(spec.find)
+        };
+        let header = spec
+            .header
+            .map(|h| HeaderName::from_bytes(h.as_bytes()))
+            .transpose()?;
+        Ok(Rule {
+            name: spec.name,
+            find,
+            replace: spec.replace,
+            host: spec.host.map(|h| h.to_ascii_lowercase()),
+            path: spec.path,
+            content_type: spec.content_type.unwrap_or_else(|| "text/".to_string()),
+            header,
+            when: spec.when,
+        })
+    }
+
+    fn applies_to(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        self.host.as_ref().is_none_or(|h| {
+            host == h || (host.ends_with(h.as_str()) && host[..host.len() - h.len()].ends_with('.'))
+        }) && self
+            .path
+            .as_ref()
+            .is_none_or(|p| url.path().starts_with(p.as_str()))
+    }
+
+    fn apply(&self, text: String) -> String {
+        let text = match &self.find {
+            Find::Literal(find) if text.contains(find.as_str()) => {
+                text.replace(find.as_str(), &self.replace)
+            }
+            Find::Regex(re) if re.is_match(&text) => {
+                re.replace_all(&text, self.replace.as_str()).into_owned()
+            }
+            _ => return text,
+        };
+        debug!("applied rewrite rule {}", self.name);
+        text
+    }
+}
+
+// An ordered list of rewrite rules
+#[derive(Debug, Default)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: RulesFile = toml::from_str(text)?;
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                let name = if spec.name.is_empty() {
+                    format!("rule[{i}]")
+                } else {
+                    spec.name.clone()
+                };
+                let mut rule = Rule::new(spec).with_context(|| format!("bad rule {name}"))?;
+                rule.name = name;
+                Ok(rule)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Rules(rules))
+    }
+
+    pub fn defaults() -> Self {
+        Rules::parse(DEFAULT_RULES).expect("built-in rules are valid")
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    // Whether a body rule may apply to a response, so it has to be read in
+    // full rather than streamed through
+    pub fn wants_body(&self, url: &Url, content_type: &str) -> bool {
+        self.0.iter().any(|rule| {
+            rule.header.is_none()
+                && content_type.starts_with(&rule.content_type)
+                && rule.applies_to(url)
+        })
+    }
+
+    // Rewrite the body of a page at `url`
+    pub fn body(&self, url: &Url, content_type: &str, mut body: String) -> String {
+        for rule in &self.0 {
+            if rule.header.is_none()
+                && content_type.starts_with(&rule.content_type)
+                && rule.applies_to(url)
+                && rule.when.as_ref().is_none_or(|w| body.contains(w.as_str()))
+            {
+                body = rule.apply(body);
+            }
+        }
+        body
+    }
+
+    // Rewrite a response header of a page at `url`
+    pub fn header(&self, url: &Url, name: &HeaderName, mut value: String) -> String {
+        for rule in &self.0 {
+            if rule.header.as_ref() == Some(name) && rule.applies_to(url) {
+                value = rule.apply(value);
+            }
+        }
+        value
+    }
+}
+
+// Holds the built-in rules plus those from the rules file, reloading the
+// file when it changes. A broken file keeps the previous rules in force.
+pub struct Rewriter {
+    config: config::Rewrite,
+    current: RwLock<Arc<Rules>>,
+    mtime: Mutex<Option<SystemTime>>,
+}
+
+impl std::fmt::Debug for Rewriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rewriter")
+            .field("config", &self.config)
+            .field("rules", &self.rules().count())
+            .finish()
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Rewriter {
+    pub fn new(config: &config::Rewrite) -> Result<Self> {
+        let rules = Self::load(config)?;
+        Ok(Rewriter {
+            config: config.clone(),
+            current: RwLock::new(Arc::new(rules)),
+            mtime: Mutex::new(config.rules.as_deref().and_then(mtime)),
+        })
+    }
+
+    fn load(config: &config::Rewrite) -> Result<Rules> {
+        let mut rules = if config.defaults {
+            Rules::defaults()
+        } else {
+            Rules::default()
+        };
+        if let Some(path) = &config.rules {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let extra =
+                Rules::parse(&text).with_context(|| format!("bad rules {}", path.display()))?;
+            rules.0.extend(extra.0);
+        }
+        Ok(rules)
+    }
+
+    pub fn rules(&self) -> Arc<Rules> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Poll the rules file for changes
+    pub fn watch(self: Arc<Self>) {
+        let Some(path) = self.config.rules.clone() else {
+            return;
+        };
+        let interval = Duration::from_secs(self.config.reload_secs.max(1));
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = mtime(&path);
+                if *self.mtime.lock().unwrap() == now {
+                    continue;
+                }
+                *self.mtime.lock().unwrap() = now;
+                match Self::load(&self.config) {
+                    Ok(rules) => {
+                        info!(
+                            "reloaded {} rewrite rules from {}",
+                            rules.count(),
+                            path.display()
+                        );
+                        *self.current.write().unwrap() = Arc::new(rules);
+                    }
+                    Err(e) => warn!("failed to reload rewrite rules: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOKLINK: &str = include_str!("../tests/fixtures/booklink.html");
+
+    #[test]
+    fn test_default_rules() {
+        let rules = Rules::defaults();
+        let url = Url::parse("https://m.booklink.me/search.php?q=1").unwrap();
+        let page = rules.body(&url, "text/html; charset=utf-8", BOOKLINK.to_string());
+        assert!(page.contains("<li class=\"\">"));
+        assert!(page.contains(r#"action="/search?q=site:booklink.me""#));
+        assert!(page.contains("<span class=\"grey\"><font>2024-01-01"));
+        assert!(page.contains("<body><style>ul.list.sec {display: none;}</style>"));
+
+        // Site specific rules stay on their site
+        let other = Url::parse("https://notbooklink.me/").unwrap();
+        let page = rules.body(&other, "text/html", BOOKLINK.to_string());
+        assert!(page.contains("<li class=\"hla\">") && page.contains("www.google.com/search"));
+        // Only pages are read in full; stylesheets and scripts stream through
+        let page = rules.body(&url, "application/javascript", BOOKLINK.to_string());
+        assert_eq!(page, BOOKLINK);
+        assert!(rules.wants_body(&url, "text/html; charset=utf-8"));
+        assert!(!rules.wants_body(&url, "text/css"));
+        assert!(!rules.wants_body(&url, "text/javascript"));
+
+        let location = rules.header(
+            &url,
+            &hyper::header::LOCATION,
+            "http://www.wcxsw.org/book/1.html".to_string(),
+        );
+        assert_eq!(location, "https://m.wcxsw.org/book/1.html");
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = Rules::parse(
+            r#"
+            [[rule]]
+            find = '<a href="/ad/(\d+)">'
+            replace = '<a data-ad="$1">'
+            regex = true
+            path = "/book"
+
+            [[rule]]
+            find = "max-age=600"
+            replace = "max-age=60"
+            header = "cache-control"
+            host = "example.com"
+
+            [[rule]]
+            find = "var ad"
+            replace = "var no"
+            content_type = "application/javascript"
+            "#,
+        )
+        .unwrap();
+        let book = Url::parse("https://www.example.com/book/1.html").unwrap();
+        let home = Url::parse("https://www.example.com/").unwrap();
+        let page = r#"<a href="/ad/42">x</a>"#.to_string();
+        assert_eq!(
+            rules.body(&book, "text/html", page.clone()),
+            r#"<a data-ad="42">x</a>"#
+        );
+        assert_eq!(rules.body(&home, "text/html", page.clone()), page);
+        assert_eq!(
+            rules.body(&home, "application/javascript", "var ad=1".to_string()),
+            "var no=1"
+        );
+        assert!(rules.wants_body(&home, "application/javascript"));
+        assert!(!rules.wants_body(&home, "text/css"));
+        // Rules without a content_type take any text/
+        assert!(rules.wants_body(&book, "text/css"));
+        let cc = HeaderName::from_static("cache-control");
+        assert_eq!(
+            rules.header(&book, &cc, "max-age=600".to_string()),
+            "max-age=60"
+        );
+
+        assert!(Rules::parse("[[rule]]\nfind = \"(\"\nregex = true").is_err());
+        assert!(Rules::parse("[[rule]]\nfind = \"\"").is_err());
+        assert!(Rules::parse("[[rule]]\nfind = \"a\"\nscope = \"b\"").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use html5ever::tendril::TendrilSink;
+use hyper::body::Bytes;
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, Response, StatusCode};
+use log::{debug, warn};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use url::Url;
+
+use crate::adblock::Selector;
+use crate::assets;
+use crate::body::{self, Body};
+use crate::config::{self, SearchMode};
+use crate::utils::{self, escape_html};
+use crate::{AppContext, fetch_novel};
+
+// Results listed per site
+const MAX_RESULTS: usize = 30;
+
+// Title and url of each result
+type Found = Vec<(String, Url)>;
+
+// How to search one upstream site
+#[derive(Debug)]
+pub struct Source {
+    url: String,
+    charset: &'static Encoding,
+    results: Selector,
+}
+
+impl Source {
+    pub fn new(c: &config::SiteSearch) -> Result<Self> {
+        let charset = match &c.charset {
+            Some(label) => Encoding::for_label(label.as_bytes())
+                .with_context(|| format!("unknown charset {label:?}"))?,
+            None => encoding_rs::UTF_8,
+        };
+        Ok(Source {
+            url: c.url.clone(),
+            charset,
+            results: Selector::parse(&c.results)?,
+        })
+    }
+
+    // The results on a search page, which comes in the site's charset
+    fn results(&self, page: &[u8], base: &Url) -> Found {
+        let (html, _, _) = self.charset.decode(page);
+        results(&html, base, &self.results)
+    }
+}
+
+// Fill {query} in a search url, encoding the terms in `charset`
+fn fill(template: &str, query: &str, charset: &'static Encoding) -> String {
+    let (bytes, _, _) = charset.encode(query);
+    let encoded: String = url::form_urlencoded::byte_serialize(&bytes).collect();
+    template.replace("{query}", &encoded)
+}
+
+// The terms without the site: filters search engines use
+fn terms(query: &str) -> String {
+    query
+        .split_whitespace()
+        .filter(|w| !w.starts_with("site:"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Answer /search?q=: off to the search engine, or a page of results from
+// the upstream sites
+pub async fn respond(
+    context: &AppContext,
+    headers: &HeaderMap,
+    query: &str,
+) -> Result<Response<Body>> {
+    if context.search.mode == SearchMode::Redirect && !query.is_empty() {
+        let location = fill(&context.search.engine, query, encoding_rs::UTF_8);
+        debug!("search redirect: {}", location);
+        return Ok(Response::builder()
+            .status(StatusCode::FOUND)
+            .header(hyper::header::LOCATION, location)
+            .body(body::full(Bytes::new()))?);
+    }
+    let query = terms(query);
+    let mut sections = Vec::new();
+    if !query.is_empty() {
+        let query = query.as_str();
+        let searches = context.sites.iter().filter_map(|site| {
+            let source = site.search.as_ref()?;
+            Some(async move {
+                let found = search(context, source, query).await;
+                if let Err(e) = &found {
+                    warn!("search on {} failed: {:#}", site.name, e);
+                }
+                (site.name.as_str(), found)
+            })
+        });
+        sections = futures_util::future::join_all(searches).await;
+    }
+    let html = render(&context.fontsize, &query, &sections);
+    let mut resp = Response::new(body::full(Bytes::new()));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    let page = utils::encode_body(headers, resp.headers_mut(), html.into_bytes())?;
+    *resp.body_mut() = body::full(page);
+    Ok(resp)
+}
+
+async fn search(context: &AppContext, source: &Source, query: &str) -> Result<Found> {
+    let url = fill(&source.url, query, source.charset);
+    let page = fetch_novel(context, &url).await?;
+    let base = Url::parse(&url)?;
+    Ok(source.results(&page, &base))
+}
+
+// Title and absolute url of each result link on a search page
+fn results(html: &str, base: &Url, selector: &Selector) -> Found {
+    let link = Selector::parse("a[href]").expect("valid selector");
+    let dom = html5ever::parse_document(RcDom::default(), Default::default()).one(html);
+    let mut found = Found::new();
+    for node in selector.select(&dom.document) {
+        let a = if is_link(&node) {
+            node
+        } else {
+            match link.select(&node).into_iter().next() {
+                Some(a) => a,
+                None => continue,
+            }
+        };
+        let Some(url) = href(&a).and_then(|h| base.join(&h).ok()) else {
+            continue;
+        };
+        let title = text(&a);
+        if title.is_empty()
+            || !matches!(url.scheme(), "http" | "https")
+            || found.iter().any(|(_, u)| *u == url)
+        {
+            continue;
+        }
+        found.push((title, url));
+        if found.len() == MAX_RESULTS {
+            break;
+        }
+    }
+    found
+}
+
+fn is_link(node: &Handle) -> bool {
+    matches!(&node.data, NodeData::Element { name, .. } if name.local.as_ref() == "a")
+        && href(node).is_some()
+}
+
+fn href(node: &Handle) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|a| a.name.local.as_ref() == "href")
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+// The text under a node with whitespace collapsed
+fn text(node: &Handle) -> String {
+    fn collect(node: &Handle, out: &mut String) {
+        if let NodeData::Text { contents } = &node.data {
+            out.push_str(&contents.borrow());
+        }
+        for child in node.children.borrow().iter() {
+            collect(child, out);
+        }
+    }
+    let mut out = String::new();
+    collect(node, &mut out);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn render(fontsize: &str, query: &str, sections: &[(&str, Result<Found>)]) -> String {
+    let mut list = String::new();
+    for (name, found) in sections {
+        list.push_str(&format!("<h4>{}</h4>", escape_html(name)));
+        match found {
+            Ok(found) if found.is_empty() => list.push_str("<p>没有结果</p>"),
+            Ok(found) => {
+                for (title, url) in found {
+                    let dest: String =
+                        url::form_urlencoded::byte_serialize(url.as_str().as_bytes()).collect();
+                    list.push_str(&format!(
+                        r#"<p><a href="/?dest={dest}">{}</a></p>"#,
+                        escape_html(title)
+                    ));
+                }
+            }
+            Err(_) => list.push_str("<p>搜索失败</p>"),
+        }
+    }
+    let title = if query.is_empty() {
+        "搜索".to_string()
+    } else {
+        format!("搜索：{}", escape_html(query))
+    };
+    format!(
+        r#"<html><head><meta charset="utf-8"><meta name="viewport" content="width=device-width, initial-scale=1.0" /><title>{title}</title>{stylesheet}<style> p{{font-size:{fontsize}px;}}</style></head><body><h3>{title}</h3><form action="/search" method="get"><input type="search" name="q" value="{query}"> <button type="submit">搜索</button></form>{list}
+{theme}
+</body></html>"#,
+        query = escape_html(query),
+        stylesheet = assets::stylesheet(),
+        theme = assets::script("theme.js"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOKLINK: &str = include_str!("../tests/fixtures/booklink.html");
+    const GBK: &[u8] = include_bytes!("../tests/fixtures/search-gbk.html");
+
+    #[test]
+    fn test_fill() {
+        let gbk = Encoding::for_label(b"gbk").unwrap();
+        assert_eq!(
+            fill("https://a.com/s?q={query}", "斗破 苍穹", gbk),
+            "https://a.com/s?q=%B6%B7%C6%C6+%B2%D4%F1%B7"
+        );
+        assert_eq!(
+            fill("https://a.com/s?q={query}", "a&b", encoding_rs::UTF_8),
+            "https://a.com/s?q=a%26b"
+        );
+        assert_eq!(terms(" site:booklink.me  斗破 "), "斗破");
+    }
+
+    #[test]
+    fn test_gbk_results() {
+        let source = Source::new(&config::SiteSearch {
+            url: "https://www.example.com/s.php?q={query}".to_string(),
+            charset: Some("gbk".to_string()),
+            results: "div.result".to_string(),
+        })
+        .unwrap();
+        let base = Url::parse("https://www.example.com/s.php?q=1").unwrap();
+        let found = source.results(GBK, &base);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, "斗破苍穹");
+        assert_eq!(found[1].1.as_str(), "https://www.example.com/book/67890/");
+    }
+
+    #[test]
+    fn test_results() {
+        let base = Url::parse("https://m.booklink.me/search.php?q=1").unwrap();
+        let found = results(BOOKLINK, &base, &Selector::parse("ul.list > li").unwrap());
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, "第一章 开始");
+        assert_eq!(found[0].1.as_str(), "https://m.booklink.me/book-1-100.html");
+
+        let page = render("17", "<x>", &[("booklink", Ok(found))]);
+        assert!(page.contains(r#"value="&lt;x&gt;""#));
+        assert!(page.contains(
+            r#"<a href="/?dest=https%3A%2F%2Fm.booklink.me%2Fbook-1-100.html">第一章 开始</a>"#
+        ));
+    }
+}
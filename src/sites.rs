@@ -0,0 +1,265 @@
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::config::{Replace, Upstream};
+use crate::search;
+
+// Follows the reader's light/dark preference on proxied pages
+const DARK_MODE: &str = r#"
+                <script type="text/javascript">
+                function chg(e) {
+                    if (e === "dark") {
+                        document.body.style.color = "white";
+                        document.body.style.backgroundColor = "black";
+                        var all = document.querySelectorAll('a');
+                        var top = document.querySelectorAll('a.top');
+                        [].slice.call(all).forEach(function(elem) {
+                            elem.style.color = '#338dff';
+                        });
+                        [].slice.call(top).forEach(function(elem) {
+                            elem.style.color = '#f00';
+                        });
+                        var elements = document.getElementsByClassName('grey');
+                        [].slice.call(elements).forEach(function(elem) {
+                            elem.style.color = '#a9a196';
+                        });
+                    } else {
+                        document.body.style.color = "black";
+                        document.body.style.backgroundColor = "white";
+                        var all = document.querySelectorAll('a');
+                        var top = document.querySelectorAll('a.top');
+                        [].slice.call(all).forEach(function(elem) {
+                            elem.style.color = '#03f';
+                        });
+                        [].slice.call(top).forEach(function(elem) {
+                            elem.style.color = '#f00';
+                        });
+                        var elements = document.getElementsByClassName('grey');
+                        [].slice.call(elements).forEach(function(elem) {
+                            elem.style.color = '#646464';
+                        });
+                    }
+                }
+                if (window.matchMedia && window.matchMedia('(prefers-color-scheme: dark)').matches) {
+                    chg("dark");
+                } else {
+                    chg("light");
+                }
+                window.matchMedia('(prefers-color-scheme: dark)').addEventListener('change', event => {
+                    const newColorScheme = event.matches ? "dark" : "light";
+                    chg(newColorScheme);
+                });
+                </script>
+                "#;
+
+pub struct Site {
+    pub name: String,
+    // Scheme and authority, without a trailing slash
+    pub url: String,
+    hosts: Vec<String>,
+    // "" when the site is not mounted under a path
+    pub prefix: String,
+    pub cookie_domain: String,
+    pub replace: Vec<Replace>,
+    // Inserted before </body>
+    pub inject: String,
+    pub search: Option<search::Source>,
+}
+
+impl std::fmt::Debug for Site {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Site")
+            .field("name", &self.name)
+            .field("url", &self.url)
+            .field("hosts", &self.hosts)
+            .field("prefix", &self.prefix)
+            .field("cookie_domain", &self.cookie_domain)
+            .field("replace", &self.replace.len())
+            .field("search", &self.search)
+            .finish()
+    }
+}
+
+impl Site {
+    fn new(u: Upstream) -> Result<Self> {
+        let url = url::Url::parse(&u.url)?;
+        let host = url.host_str().context("upstream url has no host")?;
+        let cookie_domain = u.cookie_domain.unwrap_or_else(|| {
+            let domain = host.strip_prefix("m.").unwrap_or(host);
+            format!(".{}", domain.strip_prefix("www.").unwrap_or(domain))
+        });
+        let mut inject = String::new();
+        if u.dark_mode {
+            inject.push_str(DARK_MODE);
+        }
+        for path in &u.scripts {
+            let script = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            inject.push_str(&format!(
+                "<script type=\"text/javascript\">\n{script}\n</script>\n"
+            ));
+        }
+        let search = u.search.as_ref().map(search::Source::new).transpose()?;
+        Ok(Site {
+            name: u.name,
+            url: u.url.trim_end_matches('/').to_string(),
+            hosts: u.hosts.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            prefix: u.prefix.unwrap_or_default(),
+            cookie_domain,
+            replace: u.replace,
+            inject,
+            search,
+        })
+    }
+
+    // What follows the prefix, if `path` is under it: /ex covers /ex,
+    // /ex/a and /ex?q=1 but not /exit
+    fn unmount<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.prefix.is_empty() {
+            return None;
+        }
+        path.strip_prefix(&self.prefix)
+            .filter(|rest| rest.is_empty() || rest.starts_with(['/', '?']))
+    }
+
+    fn mounted_at(&self, path: &str) -> bool {
+        self.unmount(path).is_some()
+    }
+
+    // The upstream url for a path and query received by us
+    pub fn forward(&self, path_and_query: &str) -> String {
+        let rest = self.unmount(path_and_query).unwrap_or(path_and_query);
+        if rest.starts_with('/') {
+            format!("{}{}", self.url, rest)
+        } else {
+            format!("{}/{}", self.url, rest)
+        }
+    }
+
+    // Point root-relative links in a page at the site's mount point
+    pub fn mount_links(&self, html: &str) -> String {
+        static LINK: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r#"(?i)\b(href|src|action)=(["'])/([^/])"#).unwrap());
+        if self.prefix.is_empty() {
+            return html.to_string();
+        }
+        LINK.replace_all(html, format!("$1=$2{}/$3", self.prefix))
+            .into_owned()
+    }
+
+    // Map a Location on the upstream back to our side, if it is one
+    pub fn mount_location(&self, location: &str) -> Option<String> {
+        let path = match location.strip_prefix(&self.url) {
+            Some(rest) if rest.is_empty() || rest.starts_with(['/', '?']) => rest,
+            _ if location.starts_with('/') && !location.starts_with("//") => location,
+            _ => return None,
+        };
+        Some(format!("{}{}", self.prefix, path))
+    }
+}
+
+// The upstream sites one instance fronts
+#[derive(Debug)]
+pub struct Sites(Vec<Site>);
+
+impl Sites {
+    pub fn new(upstreams: Vec<Upstream>) -> Result<Self> {
+        let sites = upstreams
+            .into_iter()
+            .map(|u| {
+                let name = u.name.clone();
+                Site::new(u).with_context(|| format!("bad upstream {name}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        anyhow::ensure!(!sites.is_empty(), "no upstream configured");
+        Ok(Sites(sites))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Site> {
+        self.0.iter()
+    }
+
+    // Pick the site for a request by its Host header and path. Sites that
+    // name hosts or a prefix win over catch-alls. Without a catch-all the
+    // first site not under a prefix is the last resort, as a prefixed site
+    // can't take paths outside its prefix.
+    pub fn select(&self, host: Option<&str>, path: &str) -> &Site {
+        let host = host
+            .and_then(|h| h.parse::<hyper::http::uri::Authority>().ok())
+            .map(|a| a.host().to_ascii_lowercase());
+        let matches = |s: &&Site| {
+            (s.hosts.is_empty() || host.as_ref().is_some_and(|h| s.hosts.contains(h)))
+                && (s.prefix.is_empty() || s.mounted_at(path))
+        };
+        self.0
+            .iter()
+            .filter(matches)
+            .find(|s| !s.hosts.is_empty() || !s.prefix.is_empty())
+            .or_else(|| self.0.iter().find(matches))
+            .or_else(|| self.0.iter().find(|s| s.prefix.is_empty()))
+            .unwrap_or(&self.0[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sites() -> Sites {
+        let mut a = Upstream::from_booksite("https://m.booklink.me");
+        a.name = "booklink".to_string();
+        let mut b = Upstream::from_booksite("https://www.example.com/");
+        b.name = "example".to_string();
+        b.prefix = Some("/ex".to_string());
+        let mut c = Upstream::from_booksite("https://novels.example.org");
+        c.name = "novels".to_string();
+        c.hosts = vec!["novels.local".to_string()];
+        Sites::new(vec![a, b, c]).unwrap()
+    }
+
+    #[test]
+    fn test_select() {
+        let sites = sites();
+        assert_eq!(sites.select(None, "/").name, "booklink");
+        assert_eq!(
+            sites.select(Some("reader.local"), "/ex/a.html").name,
+            "example"
+        );
+        assert_eq!(sites.select(None, "/ex").name, "example");
+        assert_eq!(sites.select(None, "/exit").name, "booklink");
+        assert_eq!(sites.select(Some("Novels.local:9005"), "/").name, "novels");
+        assert_eq!(sites.0[0].cookie_domain, ".booklink.me");
+        assert_eq!(sites.0[1].cookie_domain, ".example.com");
+
+        // Without a catch-all, not to a site whose prefix isn't in the path
+        let sites = Sites(sites.0.into_iter().skip(1).collect());
+        assert_eq!(sites.select(Some("other.local"), "/exit").name, "novels");
+    }
+
+    #[test]
+    fn test_mount() {
+        let sites = sites();
+        let ex = &sites.0[1];
+        assert_eq!(
+            ex.forward("/ex/b/1.html?p=2"),
+            "https://www.example.com/b/1.html?p=2"
+        );
+        assert_eq!(ex.forward("/ex"), "https://www.example.com/");
+        assert_eq!(ex.forward("/ex?p=2"), "https://www.example.com/?p=2");
+        assert_eq!(ex.forward("/exit"), "https://www.example.com/exit");
+        assert_eq!(sites.0[0].forward("/s?q=1"), "https://m.booklink.me/s?q=1");
+        assert_eq!(
+            ex.mount_links(r#"<a href="/b/1.html"><img src='/i.png'><a href="//cdn/x">"#),
+            r#"<a href="/ex/b/1.html"><img src='/ex/i.png'><a href="//cdn/x">"#
+        );
+        assert_eq!(
+            ex.mount_location("https://www.example.com/b?x=1")
+                .as_deref(),
+            Some("/ex/b?x=1")
+        );
+        assert_eq!(ex.mount_location("/b").as_deref(), Some("/ex/b"));
+        assert_eq!(ex.mount_location("https://other.com/b"), None);
+    }
+}
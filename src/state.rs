@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::AppContext;
+
+// What is kept in cache.dir across restarts
+const COOKIES: &str = "cookies.txt";
+const HTTP_CACHE: &str = "http-cache.jsonl";
+
+// Pick up the cookie jar and HTTP cache saved by the last run. A missing
+// or damaged file only costs us what was in it.
+pub fn load(context: &AppContext, dir: &Path) {
+    if let Some(jar) = &context.cookies {
+        match read(&dir.join(COOKIES)).and_then(|t| t.map(|t| jar.import(&t)).transpose()) {
+            Ok(Some(n)) => info!("loaded {} saved cookie(s)", n),
+            Ok(None) => {}
+            Err(e) => warn!("saved cookies: {:#}", e),
+        }
+    }
+    if let Some(cache) = &context.http_cache {
+        match read(&dir.join(HTTP_CACHE)) {
+            Ok(Some(text)) => info!("loaded {} saved HTTP cache entries", cache.import(&text)),
+            Ok(None) => {}
+            Err(e) => warn!("saved HTTP cache: {:#}", e),
+        }
+    }
+}
+
+// Save the cookie jar and HTTP cache for the next run
+pub fn save(context: &AppContext, dir: &Path) {
+    if let Some(jar) = &context.cookies
+        && let Err(e) = write(&dir.join(COOKIES), &jar.export())
+    {
+        warn!("{:#}", e);
+    }
+    if let Some(cache) = &context.http_cache
+        && let Err(e) = write(&dir.join(HTTP_CACHE), &cache.export())
+    {
+        warn!("{:#}", e);
+    }
+}
+
+fn read(path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+// Write through a temporary file, so a crash leaves the old file whole
+fn write(path: &Path, contents: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))
+        .and_then(|()| std::fs::write(&tmp, contents))
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .with_context(|| format!("failed to save {}", path.display()))?;
+    info!("saved {}", path.display());
+    Ok(())
+}
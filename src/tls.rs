@@ -0,0 +1,214 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use log::{debug, info, warn};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config;
+
+// How long a client gets to complete the TLS handshake, so connections
+// that never finish it don't pile up
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a connection to the redirect port may stay open
+const REDIRECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // Plain HTTP port that redirects to HTTPS
+    pub redirect_port: Option<u16>,
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    // TLS is on when both tls.cert and tls.key are set
+    pub fn new(config: &config::Tls) -> Option<Self> {
+        Some(TlsConfig {
+            cert: config.cert.clone()?,
+            key: config.key.clone()?,
+            redirect_port: config.redirect_port,
+            reload_interval: Duration::from_secs(config.reload_secs),
+        })
+    }
+}
+
+// The current certificate; replaced in place on reload so that new
+// handshakes pick it up while established connections carry on.
+pub struct CertStore {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+    mtimes: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl std::fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertStore")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_key(config: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .with_context(|| format!("failed to read {}", config.cert.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("bad certificate in {}", config.cert.display()))?;
+    anyhow::ensure!(
+        !certs.is_empty(),
+        "no certificate in {}",
+        config.cert.display()
+    );
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .with_context(|| format!("failed to read key from {}", config.key.display()))?;
+    Ok(CertifiedKey::from_der(certs, key, provider)?)
+}
+
+impl CertStore {
+    pub fn load(config: TlsConfig) -> Result<Arc<Self>> {
+        let provider = rustls::ServerConfig::builder().crypto_provider().clone();
+        let key = load_key(&config, &provider)?;
+        let mtimes = (mtime(&config.cert), mtime(&config.key));
+        Ok(Arc::new(CertStore {
+            config,
+            provider,
+            current: RwLock::new(Arc::new(key)),
+            mtimes: Mutex::new(mtimes),
+        }))
+    }
+
+    pub fn reload(&self) -> Result<()> {
+        let key = load_key(&self.config, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        *self.mtimes.lock().unwrap() = (mtime(&self.config.cert), mtime(&self.config.key));
+        info!("reloaded certificate from {}", self.config.cert.display());
+        Ok(())
+    }
+
+    fn changed(&self) -> bool {
+        let now = (mtime(&self.config.cert), mtime(&self.config.key));
+        *self.mtimes.lock().unwrap() != now
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        // Offer HTTP/2 first so browsers multiplex over one connection
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    // Reload the certificate when its files change or on SIGHUP
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+            loop {
+                #[cfg(unix)]
+                let forced = match hup.as_mut() {
+                    Some(hup) => tokio::select! {
+                        _ = hup.recv() => true,
+                        _ = tokio::time::sleep(self.config.reload_interval) => false,
+                    },
+                    None => {
+                        tokio::time::sleep(self.config.reload_interval).await;
+                        false
+                    }
+                };
+                #[cfg(not(unix))]
+                let forced = {
+                    tokio::time::sleep(self.config.reload_interval).await;
+                    false
+                };
+                if (forced || self.changed())
+                    && let Err(e) = self.reload()
+                {
+                    // Keep serving the old certificate
+                    warn!("failed to reload certificate: {:#}", e);
+                    *self.mtimes.lock().unwrap() =
+                        (mtime(&self.config.cert), mtime(&self.config.key));
+                }
+            }
+        });
+    }
+}
+
+// Redirect plain HTTP requests to the HTTPS site. `host` is used when the
+// request has no Host header, `port` is the public HTTPS port, empty for 443.
+pub async fn redirect(listener: TcpListener, host: String, port: String) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept redirect connection: {}", e);
+                tokio::time::sleep(crate::listener::ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let (default_host, port) = (host.clone(), port.clone());
+        let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+            let (default_host, port) = (default_host.clone(), port.clone());
+            async move {
+                let host = req
+                    .headers()
+                    .get(hyper::header::HOST)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.parse::<hyper::http::uri::Authority>().ok())
+                    .map(|a| a.host().to_string())
+                    .unwrap_or(default_host);
+                let path = req
+                    .uri()
+                    .path_and_query()
+                    .map(|p| p.as_str())
+                    .unwrap_or("/");
+                let location = if port.is_empty() || port == "443" {
+                    format!("https://{host}{path}")
+                } else {
+                    format!("https://{host}:{port}{path}")
+                };
+                debug!("redirect to {}", &location);
+                Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(hyper::header::LOCATION, location)
+                    .body(Full::new(Bytes::new()))
+            }
+        });
+        tokio::spawn(async move {
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            match tokio::time::timeout(REDIRECT_TIMEOUT, conn).await {
+                Ok(Err(err)) => debug!("Failed to serve redirect connection: {:?}", err),
+                Err(_) => debug!("Redirect connection timed out"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
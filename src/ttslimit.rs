@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::info;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::AppError;
+use crate::{config, metrics};
+
+// When to come back after hitting a concurrency cap
+const BUSY_RETRY: Duration = Duration::from_secs(10);
+
+// How long a chapter waits for free connections to the speech service
+const QUEUE_WAIT: Duration = Duration::from_secs(10);
+
+struct Client {
+    // Characters left, refilled at chars_per_minute
+    chars: f64,
+    last: Instant,
+    listening: usize,
+}
+
+// Limits on speech synthesis, so a few busy readers can't get our address
+// blocked by the speech service: characters per minute and ?listen=
+// requests in flight per client, and connections in flight overall
+pub struct TtsLimiter {
+    chars_per_minute: f64,
+    listens: usize,
+    // Connections one chapter takes
+    chunks: u32,
+    clients: Mutex<HashMap<String, Client>>,
+    connections: Arc<Semaphore>,
+}
+
+impl std::fmt::Debug for TtsLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TtsLimiter")
+            .field("chars_per_minute", &self.chars_per_minute)
+            .field("listens", &self.listens)
+            .field("connections", &self.connections.available_permits())
+            .finish()
+    }
+}
+
+// A ?listen= request in flight, counted until dropped
+pub struct Listening<'a> {
+    limiter: &'a TtsLimiter,
+    client: String,
+}
+
+impl Drop for Listening<'_> {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(c) = clients.get_mut(&self.client) {
+            c.listening = c.listening.saturating_sub(1);
+        }
+    }
+}
+
+impl TtsLimiter {
+    pub fn new(config: &config::Tts) -> Self {
+        TtsLimiter {
+            chars_per_minute: config.chars_per_minute as f64,
+            listens: config.listens_per_client,
+            chunks: config.chunks as u32,
+            clients: Mutex::new(HashMap::new()),
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+        }
+    }
+
+    fn refill(&self, c: &mut Client) {
+        let now = Instant::now();
+        let per_sec = self.chars_per_minute / 60.0;
+        c.chars = f64::min(
+            self.chars_per_minute,
+            c.chars + now.duration_since(c.last).as_secs_f64() * per_sec,
+        );
+        c.last = now;
+    }
+
+    fn client<'m>(&self, clients: &'m mut HashMap<String, Client>, key: &str) -> &'m mut Client {
+        clients.entry(key.to_string()).or_insert_with(|| Client {
+            chars: self.chars_per_minute,
+            last: Instant::now(),
+            listening: 0,
+        })
+    }
+
+    // Count a ?listen= request from `client`, or refuse it if the client
+    // has too many going already
+    pub fn listen(&self, client: &str) -> Result<Listening<'_>> {
+        let mut clients = self.clients.lock().unwrap();
+        // Forget clients that are idle with a full allowance
+        clients.retain(|_, c| {
+            self.refill(c);
+            c.listening > 0 || c.chars < self.chars_per_minute
+        });
+        let c = self.client(&mut clients, client);
+        if c.listening >= self.listens {
+            info!("{} has {} listen requests going", client, c.listening);
+            metrics::tts_rejected("listens");
+            return Err(AppError::TooManyRequests {
+                retry_after: BUSY_RETRY,
+            }
+            .into());
+        }
+        c.listening += 1;
+        Ok(Listening {
+            limiter: self,
+            client: client.to_string(),
+        })
+    }
+
+    // Take `chars` characters from the client's allowance. A chapter longer
+    // than the whole allowance goes through once the allowance is full.
+    pub fn charge(&self, client: &str, chars: usize) -> Result<()> {
+        if self.chars_per_minute == 0.0 {
+            return Ok(());
+        }
+        let mut clients = self.clients.lock().unwrap();
+        let c = self.client(&mut clients, client);
+        self.refill(c);
+        let needed = f64::min(chars as f64, self.chars_per_minute);
+        if c.chars < needed {
+            let wait = (needed - c.chars) / (self.chars_per_minute / 60.0);
+            info!("{} is over its speech allowance", client);
+            metrics::tts_rejected("chars");
+            return Err(AppError::TooManyRequests {
+                retry_after: Duration::from_secs_f64(wait.ceil()),
+            }
+            .into());
+        }
+        c.chars -= chars as f64;
+        Ok(())
+    }
+
+    // Connections for synthesizing one chapter, waiting a while for others
+    // to finish
+    pub async fn connections(&self) -> Result<OwnedSemaphorePermit> {
+        let permits = self.connections.clone().acquire_many_owned(self.chunks);
+        match tokio::time::timeout(QUEUE_WAIT, permits).await {
+            Ok(permit) => Ok(permit?),
+            Err(_) => {
+                metrics::tts_rejected("connections");
+                Err(AppError::TooManyRequests {
+                    retry_after: BUSY_RETRY,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+// The characters of chapter text that are read out
+pub fn chars(text: &str) -> usize {
+    text.replace("<p>", "").replace("</p>", "").chars().count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> TtsLimiter {
+        TtsLimiter::new(&config::Tts {
+            chars_per_minute: 6000,
+            listens_per_client: 2,
+            max_connections: 10,
+            ..Default::default()
+        })
+    }
+
+    fn retry_after(result: Result<impl Sized>) -> Option<Duration> {
+        match result.err()?.downcast_ref() {
+            Some(AppError::TooManyRequests { retry_after }) => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_listens() {
+        let limiter = limiter();
+        let a = limiter.listen("192.0.2.1").unwrap();
+        let _b = limiter.listen("192.0.2.1").unwrap();
+        assert_eq!(retry_after(limiter.listen("192.0.2.1")), Some(BUSY_RETRY));
+        let _c = limiter.listen("user:alice").unwrap();
+        drop(a);
+        limiter.listen("192.0.2.1").unwrap();
+    }
+
+    #[test]
+    fn test_chars() {
+        let limiter = limiter();
+        limiter.charge("192.0.2.1", 4000).unwrap();
+        // 2000 short, refilled at 100 a second
+        let wait = retry_after(limiter.charge("192.0.2.1", 4000)).unwrap();
+        assert!(wait > Duration::from_secs(18) && wait <= Duration::from_secs(20));
+        limiter.charge("192.0.2.2", 4000).unwrap();
+
+        // Longer than the allowance, once
+        limiter.charge("192.0.2.3", 9000).unwrap();
+        assert!(retry_after(limiter.charge("192.0.2.3", 1)).is_some());
+        assert_eq!(chars("<p>第一章</p><p>你好</p>"), 5);
+    }
+
+    #[tokio::test]
+    async fn test_connections() {
+        let limiter = limiter();
+        let permit = limiter.connections().await.unwrap();
+        assert_eq!(limiter.connections.available_permits(), 0);
+        drop(permit);
+        assert_eq!(limiter.connections.available_permits(), 10);
+    }
+}
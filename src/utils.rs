@@ -1,4 +1,3 @@
-use std::env;
 use std::io::{self, Read};
 use std::sync::Arc;
 
@@ -9,38 +8,110 @@ use futures_util::{SinkExt, StreamExt};
 use log::{debug, info};
 use rustls::ClientConfig;
 use time::{format_description, OffsetDateTime};
-use tokio::net::TcpStream;
 use tokio_rustls::TlsConnector;
-use tokio_socks::tcp::Socks5Stream;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::http::header::USER_AGENT;
 use tokio_tungstenite::tungstenite::http::HeaderValue;
 use url::Url;
 use uuid::Uuid;
 
+use crate::connect::Connector;
+use crate::error::AppError;
+use crate::metrics;
+
 const DATE_FORMAT_STR: &str = "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z";
 
-const ENDPOINT2: &str =
+type TtsStream =
+    tokio_tungstenite::WebSocketStream<tokio_rustls::client::TlsStream<tokio::net::TcpStream>>;
+
+pub const ENDPOINT2: &str =
     "wss://speech.platform.bing.com/consumer/speech/synthesize/readaloud/edge/v1";
 const PAYLOAD_2: &str = r#"{"context":{"synthesis":{"audio":{"metadataoptions":{"sentenceBoundaryEnabled":"false","wordBoundaryEnabled":"false"},"outputFormat":"audio-24khz-48kbitrate-mono-mp3"}}}}"#;
 
-// Compress the given body using Brotli
-pub fn compress_body(body: &[u8], compression_type: &mut String) -> Result<Vec<u8>> {
-    // Pre-allocate with capacity based on input size
-    let mut smallest_compressed = Vec::with_capacity(body.len());
-    let compressions = if compression_type.is_empty() {
-        vec!["br"]
-    } else {
-        let mut types: Vec<&str> = compression_type.split(',').map(str::trim).collect();
-        if !types.contains(&"br") {
-            types.push("br");
+// Content codings we can produce, preferred in this order on equal q-values
+const CODINGS: [&str; 4] = ["br", "zstd", "gzip", "deflate"];
+
+// Types worth compressing; images, audio and archives already are
+const COMPRESSIBLE: [&str; 8] = [
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/manifest+json",
+    "image/svg+xml",
+];
+
+// Smaller bodies gain too little from compression to be worth it
+const MIN_COMPRESS_SIZE: usize = 512;
+
+// What a client's Accept-Encoding allows
+#[derive(Debug, PartialEq)]
+pub struct Accepted {
+    // Our codings sharing the highest q-value
+    pub best: Vec<&'static str>,
+    // Whether the body may be sent uncompressed
+    pub identity: bool,
+}
+
+pub fn parse_accept_encoding(header: &str) -> Accepted {
+    let mut listed = Vec::new();
+    for item in header.split(',') {
+        let mut params = item.split(';');
+        let coding = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
         }
-        types
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let coding = if coding == "x-gzip" {
+            "gzip".to_string()
+        } else {
+            coding
+        };
+        listed.push((coding, q));
+    }
+    let q_of = |name: &str| {
+        listed
+            .iter()
+            .find(|(c, _)| c == name)
+            .or_else(|| listed.iter().find(|(c, _)| c == "*"))
+            .map(|(_, q)| *q)
     };
+    let mut best = Vec::new();
+    let mut top = 0.0;
+    for coding in CODINGS {
+        let q = q_of(coding).unwrap_or(0.0);
+        if q > top {
+            top = q;
+            best.clear();
+        }
+        if q > 0.0 && q == top {
+            best.push(coding);
+        }
+    }
+    Accepted {
+        best,
+        identity: q_of("identity").is_none_or(|q| q > 0.0),
+    }
+}
 
-    let mut best_compression_type = String::new();
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.trim().to_ascii_lowercase();
+    COMPRESSIBLE.iter().any(|t| mime.starts_with(t))
+}
 
-    for &compression in &compressions {
+// Compress the body with each of `codings`, keeping the smallest result
+pub fn compress_body(body: &[u8], codings: &[&'static str]) -> Result<(Vec<u8>, &'static str)> {
+    let mut smallest: Option<(Vec<u8>, &'static str)> = None;
+    for &compression in codings {
         let compressed = match compression {
             "br" => {
                 let mut output = Vec::with_capacity(body.len());
@@ -76,18 +147,87 @@ pub fn compress_body(body: &[u8], compression_type: &mut String) -> Result<Vec<u
             }
         };
 
-        debug!("compressed len: {}", compressed.len());
-        if smallest_compressed.is_empty() || compressed.len() < smallest_compressed.len() {
-            smallest_compressed = compressed;
-            best_compression_type = compression.to_string();
+        debug!("{} compressed len: {}", compression, compressed.len());
+        if smallest
+            .as_ref()
+            .is_none_or(|(best, _)| compressed.len() < best.len())
+        {
+            smallest = Some((compressed, compression));
         }
     }
+    let (compressed, compression) = smallest.context("no compression type to try")?;
+    metrics::compressed(compression, body.len(), compressed.len());
+    Ok((compressed, compression))
+}
 
-    // Update the compression type after the loop
-    compression_type.clear();
-    compression_type.push_str(&best_compression_type);
+// All of a request's Accept-Encoding headers as one list
+pub fn accept_encoding(req_headers: &hyper::HeaderMap) -> String {
+    req_headers
+        .get_all(hyper::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",")
+}
 
-    Ok(smallest_compressed)
+// Encode a response body for the request's Accept-Encoding, setting
+// Content-Encoding, Content-Length and Vary in `headers`
+pub fn encode_body(
+    req_headers: &hyper::HeaderMap,
+    headers: &mut hyper::HeaderMap,
+    body: Vec<u8>,
+) -> Result<Vec<u8>> {
+    use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+
+    let accept_encoding = accept_encoding(req_headers);
+    let accepted = parse_accept_encoding(&accept_encoding);
+    let compressible = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(is_compressible);
+    if compressible
+        && !headers
+            .get_all(VARY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case("accept-encoding"))
+    {
+        headers.append(
+            VARY,
+            hyper::header::HeaderValue::from_static("Accept-Encoding"),
+        );
+    }
+    let compress = !accepted.best.is_empty()
+        && (!accepted.identity || compressible && body.len() >= MIN_COMPRESS_SIZE);
+    let body = if compress {
+        let (compressed, coding) = compress_body(&body, &accepted.best)?;
+        headers.insert(CONTENT_ENCODING, coding.parse()?);
+        compressed
+    } else if accepted.identity {
+        headers.remove(CONTENT_ENCODING);
+        body
+    } else {
+        return Err(AppError::NotAcceptable { accept_encoding }.into());
+    };
+    headers.insert(CONTENT_LENGTH, body.len().into());
+    Ok(body)
+}
+
+// Escape text for use in HTML content and attribute values
+pub fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
 }
 
 // Convert the given bytes to UTF-8 using the specified character set
@@ -101,43 +241,8 @@ pub fn to_utf8(orig: &[u8], charset: &str) -> Result<String> {
     Ok(cow.into_owned())
 }
 
-// 获取系统代理设置并建立连接
-async fn get_proxy_stream(url: &str) -> Option<TcpStream> {
-    let proxy_url = env::var("HTTPS_PROXY")
-        .or_else(|_| env::var("https_proxy"))
-        .or_else(|_| env::var("HTTP_PROXY"))
-        .or_else(|_| env::var("http_proxy"))
-        .ok()?;
-
-    let proxy = Url::parse(&proxy_url).ok()?;
-    let target = Url::parse(url).ok()?;
-
-    // 只支持 socks5 代理
-    if proxy.scheme() != "socks5" {
-        return None;
-    }
-
-    let proxy_host = proxy.host_str()?;
-    let proxy_port = proxy.port()?;
-    let target_host = target.host_str()?;
-    let target_port = target.port().unwrap_or(443);
-
-    // 建立 SOCKS5 连接
-    let stream = Socks5Stream::connect((proxy_host, proxy_port), (target_host, target_port))
-        .await
-        .ok()?;
-
-    Some(stream.into_inner())
-}
-
-// 向语音服务发送请求并返回生成的MP3音频数据
-pub async fn get_mp3(ssml: &str) -> Result<Vec<u8>> {
-    // Define the timestamp format for the X-Timestamp header
-    let dt_fmt = format_description::parse(DATE_FORMAT_STR)?;
-
-    // Generate a unique identifier for the request
-    let uuid = Uuid::new_v4().as_simple().to_string().to_uppercase();
-
+// Open the speech service's WebSocket for connection `uuid`
+pub async fn tts_connect(connector: &Connector, uuid: &str) -> Result<TtsStream> {
     // Construct the WebSocket URL with the TrustedClientToken and X-ConnectionId parameters
     let mut url = String::from(ENDPOINT2);
     url.push_str("?TrustedClientToken=6A5AA1D4EAFF4E9FB37E23D68491D6F4");
@@ -156,14 +261,12 @@ pub async fn get_mp3(ssml: &str) -> Result<Vec<u8>> {
     );
 
     // Get TCP stream (either direct or through proxy)
-    let tcp_stream = match get_proxy_stream(&url).await { Some(proxy_stream) => {
-        proxy_stream
-    } _ => {
+    let tcp_stream = {
         let target = url.parse::<Url>()?;
         let host = target.host_str().context("No host in URL")?;
         let port = target.port().unwrap_or(443);
-        TcpStream::connect((host, port)).await?
-    }};
+        connector.tcp(true, host, port).await?
+    };
 
     // Configure TLS with rustls
     let root_store = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
@@ -190,6 +293,18 @@ pub async fn get_mp3(ssml: &str) -> Result<Vec<u8>> {
     let (ws, _) = tokio_tungstenite::client_async(req, tls_stream)
         .await
         .map_err(|e| anyhow::anyhow!("WebSocket connection failed: {}", e))?;
+    Ok(ws)
+}
+
+// 向语音服务发送请求并返回生成的MP3音频数据
+pub async fn get_mp3(connector: &Connector, ssml: &str) -> Result<Vec<u8>> {
+    // Define the timestamp format for the X-Timestamp header
+    let dt_fmt = format_description::parse(DATE_FORMAT_STR)?;
+
+    // Generate a unique identifier for the request
+    let uuid = Uuid::new_v4().as_simple().to_string().to_uppercase();
+
+    let ws = tts_connect(connector, &uuid).await?;
 
     // Split the WebSocket into a writer and reader
     let (mut writer, mut reader) = ws.split();
@@ -239,6 +354,8 @@ pub async fn get_mp3(ssml: &str) -> Result<Vec<u8>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::env;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_get_mp3() -> Result<()> {
@@ -256,7 +373,8 @@ mod tests {
             </speak>
         "#;
 
-        match get_mp3(ssml).await {
+        let connector = Connector::new(Duration::from_secs(10), Duration::from_secs(60))?;
+        match get_mp3(&connector, ssml).await {
             Ok(mp3) => {
                 assert!(!mp3.is_empty());
                 // Check if the data starts with MP3 magic number (ID3 or MPEG sync)
@@ -294,10 +412,70 @@ mod tests {
     #[test]
     fn test_compress_body() {
         let body_bytes = b"Hello, world!Hello, world!Hello, world!Hello, world!Hello, world!Hello, world!Hello, world!Hello, world!Hello, world!Hello, world!".to_vec();
-        let mut compression_type = "gzip, deflate, br, zstd".to_string();
-        let result = compress_body(&body_bytes, &mut compression_type).unwrap();
+        let accepted = parse_accept_encoding("gzip, deflate, br, zstd");
+        let (result, compression_type) = compress_body(&body_bytes, &accepted.best).unwrap();
         println!("compression_type: {}", compression_type);
         assert_eq!(compression_type, "br");
         assert!(result.len() < body_bytes.len());
     }
+
+    #[test]
+    fn test_accept_encoding() {
+        let accepted = parse_accept_encoding("gzip;q=0.8, br;q=1.0, identity");
+        assert_eq!(accepted.best, ["br"]);
+        assert!(accepted.identity);
+        assert_eq!(parse_accept_encoding("X-Gzip, compress").best, ["gzip"]);
+        assert_eq!(
+            parse_accept_encoding("*;q=0.5, br;q=0").best,
+            ["zstd", "gzip", "deflate"]
+        );
+        let none = parse_accept_encoding("");
+        assert!(none.best.is_empty() && none.identity);
+        assert!(!parse_accept_encoding("identity;q=0").identity);
+        assert!(!parse_accept_encoding("gzip, *;q=0").identity);
+
+        let page = "<p>第一章</p>".repeat(100).into_bytes();
+        let req = |v: &str| {
+            let mut h = hyper::HeaderMap::new();
+            h.insert(hyper::header::ACCEPT_ENCODING, v.parse().unwrap());
+            h
+        };
+        let typed = |t: &str| {
+            let mut h = hyper::HeaderMap::new();
+            h.insert(hyper::header::CONTENT_TYPE, t.parse().unwrap());
+            h
+        };
+        let mut headers = typed("text/html; charset=utf-8");
+        let body = encode_body(&req("gzip;q=0.5, identity"), &mut headers, page.clone()).unwrap();
+        assert_eq!(headers[hyper::header::CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[hyper::header::VARY], "Accept-Encoding");
+        assert_eq!(
+            headers[hyper::header::CONTENT_LENGTH],
+            body.len().to_string().as_str()
+        );
+
+        // Small bodies and images go out as they are
+        let mut headers = typed("text/html");
+        encode_body(&req("br"), &mut headers, b"<p>hi</p>".to_vec()).unwrap();
+        assert!(!headers.contains_key(hyper::header::CONTENT_ENCODING));
+        let mut headers = typed("image/png");
+        encode_body(&req("br"), &mut headers, page.clone()).unwrap();
+        assert!(!headers.contains_key(hyper::header::CONTENT_ENCODING));
+        assert!(!headers.contains_key(hyper::header::VARY));
+
+        // Unless the client refuses them uncompressed
+        let mut headers = typed("image/png");
+        encode_body(&req("gzip, identity;q=0"), &mut headers, page.clone()).unwrap();
+        assert_eq!(headers[hyper::header::CONTENT_ENCODING], "gzip");
+        let err = encode_body(
+            &req("compress, identity;q=0"),
+            &mut typed("text/html"),
+            page,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AppError>().map(AppError::status),
+            Some(hyper::StatusCode::NOT_ACCEPTABLE)
+        );
+    }
 }